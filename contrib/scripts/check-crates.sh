@@ -35,6 +35,8 @@ buildargs=(
     "-p nostr-sdk --features nip47,nip57 --target wasm32-unknown-unknown"
     "-p nostr-sdk --features indexeddb,webln --target wasm32-unknown-unknown"
     "-p nostr-sdk --features sqlite"
+    "-p nostr-sqlite"
+    "-p nostr-sqlite --features fts"
 )
 
 for arg in "${buildargs[@]}"; do
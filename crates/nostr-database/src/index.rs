@@ -8,6 +8,7 @@ use std::cmp::Ordering;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::iter;
 use std::sync::Arc;
+use std::time::Instant;
 
 use nostr::event::id;
 use nostr::nips::nip01::Coordinate;
@@ -24,6 +25,8 @@ use crate::Order;
 
 /// Public Key Prefix Size
 const PUBLIC_KEY_PREFIX_SIZE: usize = 8;
+/// Number of matched events between the deadline checks of the queries
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
 
 #[derive(Debug, Error)]
 enum Error {
@@ -696,11 +699,31 @@ impl InternalDatabaseIndexes {
     where
         I: IntoIterator<Item = Filter>,
     {
+        match self.internal_query_until(filters, None) {
+            Some(res) => res,
+            None => unreachable!("no deadline"),
+        }
+    }
+
+    /// Query, returning `None` if `deadline` is reached before completion
+    fn internal_query_until<I>(
+        &self,
+        filters: I,
+        deadline: Option<Instant>,
+    ) -> Option<InternalQueryResult<'_>>
+    where
+        I: IntoIterator<Item = Filter>,
+    {
+        let expired = || deadline.map_or(false, |deadline| Instant::now() >= deadline);
         let mut matching_ids: BTreeSet<&ArcEventIndex> = BTreeSet::new();
 
         for filter in filters.into_iter() {
+            if expired() {
+                return None;
+            }
+
             if filter.is_empty() {
-                return InternalQueryResult::All;
+                return Some(InternalQueryResult::All);
             }
 
             if let (Some(since), Some(until)) = (filter.since, filter.until) {
@@ -722,14 +745,20 @@ impl InternalDatabaseIndexes {
                 QueryPattern::Generic(filter) => Box::new(self.internal_generic_query(filter)),
             };
 
-            if let Some(limit) = limit {
-                matching_ids.extend(evs.take(limit))
+            let evs = evs.take(limit.unwrap_or(usize::MAX));
+            if deadline.is_some() {
+                for (i, ev) in evs.enumerate() {
+                    if i % DEADLINE_CHECK_INTERVAL == 0 && expired() {
+                        return None;
+                    }
+                    matching_ids.insert(ev);
+                }
             } else {
                 matching_ids.extend(evs)
             }
         }
 
-        InternalQueryResult::Set(matching_ids)
+        Some(InternalQueryResult::Set(matching_ids))
     }
 
     /// Query
@@ -738,7 +767,25 @@ impl InternalDatabaseIndexes {
     where
         I: IntoIterator<Item = Filter>,
     {
-        match self.internal_query(filters) {
+        self.sorted_ids(self.internal_query(filters), order)
+    }
+
+    /// Query, returning `None` if `deadline` is reached before completion
+    pub fn query_with_deadline<I>(
+        &self,
+        filters: I,
+        order: Order,
+        deadline: Instant,
+    ) -> Option<Vec<EventId>>
+    where
+        I: IntoIterator<Item = Filter>,
+    {
+        let res = self.internal_query_until(filters, Some(deadline))?;
+        Some(self.sorted_ids(res, order))
+    }
+
+    fn sorted_ids(&self, res: InternalQueryResult<'_>, order: Order) -> Vec<EventId> {
+        match res {
             InternalQueryResult::All => match order {
                 Order::Asc => self.index.iter().map(|ev| ev.event_id).rev().collect(),
                 Order::Desc => self.index.iter().map(|ev| ev.event_id).collect(),
@@ -871,6 +918,24 @@ impl DatabaseIndexes {
         inner.query(filters, order)
     }
 
+    /// Query, returning `None` if not completed before `deadline`
+    ///
+    /// The deadline is checked periodically while evaluating the filters, so an expensive
+    /// filter doesn't hold the indexes for long.
+    #[tracing::instrument(skip_all, level = "trace")]
+    pub async fn query_with_deadline<I>(
+        &self,
+        filters: I,
+        order: Order,
+        deadline: Instant,
+    ) -> Option<Vec<EventId>>
+    where
+        I: IntoIterator<Item = Filter>,
+    {
+        let inner = self.inner.read().await;
+        inner.query_with_deadline(filters, order, deadline)
+    }
+
     /// Count events
     #[tracing::instrument(skip_all, level = "trace")]
     pub async fn count<I>(&self, filters: I) -> usize
@@ -912,6 +977,14 @@ impl DatabaseIndexes {
         inner.delete(filter)
     }
 
+    /// Mark [`EventId`]s as deleted (i.e. to restore the deletions persisted by a database)
+    ///
+    /// The events are discarded from the indexes, if indexed, and won't be indexed again.
+    pub async fn mark_as_deleted(&self, ids: HashSet<EventId>) {
+        let mut inner = self.inner.write().await;
+        inner.discard_events(&ids);
+    }
+
     /// Clear indexes
     pub async fn clear(&self) {
         let mut inner = self.inner.write().await;
@@ -0,0 +1,4 @@
+-- Event kind, to filter by kind in SQL
+ALTER TABLE events ADD COLUMN kind INTEGER;
+
+CREATE INDEX IF NOT EXISTS events_kind_index ON events(kind);
@@ -0,0 +1,2 @@
+-- Event author (hex public key), to filter by author in SQL
+ALTER TABLE events ADD COLUMN author TEXT;
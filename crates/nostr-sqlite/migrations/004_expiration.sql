@@ -0,0 +1,4 @@
+-- NIP-40 expiration timestamp (NULL if the event doesn't expire)
+ALTER TABLE events ADD COLUMN expiration INTEGER;
+
+CREATE INDEX IF NOT EXISTS events_expiration_index ON events(expiration) WHERE expiration IS NOT NULL;
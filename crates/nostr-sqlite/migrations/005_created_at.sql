@@ -0,0 +1,4 @@
+-- Event creation timestamp, to filter by time in SQL
+ALTER TABLE events ADD COLUMN created_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS events_created_at_index ON events(created_at);
@@ -0,0 +1,2 @@
+-- Compression of the event value (0: none, 1: zstd)
+ALTER TABLE events ADD COLUMN compression INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- Single-letter tags (first value only), to filter by tag in SQL
+CREATE TABLE IF NOT EXISTS event_tags (
+    event_id TEXT NOT NULL,
+    tag_name CHAR(1) NOT NULL,
+    tag_value TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS event_tags_index ON event_tags(tag_name,tag_value,event_id);
+CREATE INDEX IF NOT EXISTS event_tags_event_id_index ON event_tags(event_id);
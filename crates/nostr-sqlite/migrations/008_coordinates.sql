@@ -0,0 +1,11 @@
+-- Current event of each replaceable coordinate (empty identifier if not parameterized)
+CREATE TABLE IF NOT EXISTS event_coordinates (
+    kind INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (kind, author, identifier)
+);
+
+CREATE INDEX IF NOT EXISTS event_coordinates_event_id_index ON event_coordinates(event_id);
@@ -0,0 +1,5 @@
+-- IDs of the deleted events (NIP-09, replaced or deleted by filter), restored in the indexes at startup
+CREATE TABLE IF NOT EXISTS deleted_events (
+    event_id TEXT PRIMARY KEY NOT NULL,
+    deleted_at INTEGER NOT NULL
+);
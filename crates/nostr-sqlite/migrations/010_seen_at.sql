@@ -0,0 +1,3 @@
+-- Time the event was first seen by the relay, for trimming the table (`0` for the older rows)
+ALTER TABLE event_seen_by_relays ADD COLUMN seen_at INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS event_seen_by_relays_seen_at_index ON event_seen_by_relays(seen_at);
@@ -0,0 +1,2 @@
+-- Serialization format of the event value (0: FlatBuffers, 1: JSON)
+ALTER TABLE events ADD COLUMN format INTEGER NOT NULL DEFAULT 0;
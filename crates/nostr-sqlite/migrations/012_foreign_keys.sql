@@ -0,0 +1,29 @@
+-- Delete the tags and the coordinates with their event (`ON DELETE CASCADE`). SQLite can't add
+-- a foreign key to an existing table: the tables are copied, skipping the orphan rows.
+CREATE TABLE event_tags_new (
+    event_id TEXT NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    tag_name CHAR(1) NOT NULL,
+    tag_value TEXT NOT NULL
+);
+INSERT INTO event_tags_new (event_id, tag_name, tag_value)
+    SELECT event_id, tag_name, tag_value FROM event_tags WHERE event_id IN (SELECT event_id FROM events);
+DROP TABLE event_tags;
+ALTER TABLE event_tags_new RENAME TO event_tags;
+
+CREATE UNIQUE INDEX IF NOT EXISTS event_tags_index ON event_tags(tag_name,tag_value,event_id);
+CREATE INDEX IF NOT EXISTS event_tags_event_id_index ON event_tags(event_id);
+
+CREATE TABLE event_coordinates_new (
+    kind INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    event_id TEXT NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (kind, author, identifier)
+);
+INSERT INTO event_coordinates_new (kind, author, identifier, event_id, created_at)
+    SELECT kind, author, identifier, event_id, created_at FROM event_coordinates WHERE event_id IN (SELECT event_id FROM events);
+DROP TABLE event_coordinates;
+ALTER TABLE event_coordinates_new RENAME TO event_coordinates;
+
+CREATE INDEX IF NOT EXISTS event_coordinates_event_id_index ON event_coordinates(event_id);
@@ -0,0 +1,3 @@
+-- Length of the event content, in bytes, for the size stats without decoding the events
+ALTER TABLE events ADD COLUMN content_len INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS events_content_len_index ON events(content_len);
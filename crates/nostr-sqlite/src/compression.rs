@@ -0,0 +1,49 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Compression of the stored events
+//!
+//! The compression used for each row is stored in the `compression` column of the `events`
+//! table, so rows written before enabling (or after disabling) it are still decoded.
+
+use crate::Error;
+
+/// Uncompressed value
+const NONE: u8 = 0;
+/// Zstandard compressed value
+const ZSTD: u8 = 1;
+
+/// Compress the encoded event with zstd (if a level is set)
+///
+/// Returns the value to store and its compression flag.
+pub(crate) fn compress(value: Vec<u8>, level: Option<i32>) -> Result<(Vec<u8>, u8), Error> {
+    match level {
+        #[cfg(feature = "zstd")]
+        Some(level) => Ok((zstd::bulk::compress(&value, level)?, ZSTD)),
+        _ => Ok((value, NONE)),
+    }
+}
+
+/// Compression flag of the values compressed with `level`
+pub(crate) fn flag(level: Option<i32>) -> u8 {
+    match level {
+        #[cfg(feature = "zstd")]
+        Some(_) => ZSTD,
+        _ => NONE,
+    }
+}
+
+/// Decompress a stored value, according to its compression flag
+pub(crate) fn decompress(value: Vec<u8>, compression: u8) -> Result<Vec<u8>, Error> {
+    match compression {
+        NONE => Ok(value),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(zstd::stream::decode_all(value.as_slice())?),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => Err(Error::Unsupported(String::from(
+            "zstd compressed events (`zstd` feature disabled)",
+        ))),
+        other => Err(Error::Unsupported(format!("compression {other}"))),
+    }
+}
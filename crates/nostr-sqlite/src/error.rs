@@ -2,9 +2,10 @@
 // Copyright (c) 2023-2024 Rust Nostr Developers
 // Distributed under the MIT software license
 
-use deadpool_sqlite::{CreatePoolError, InteractError, PoolError};
 use nostr_database::{flatbuffers, DatabaseError};
+use rusqlite::ErrorCode;
 use thiserror::Error;
+use tokio::task::JoinError;
 
 use crate::migration::MigrationError;
 
@@ -12,17 +13,33 @@ use crate::migration::MigrationError;
 #[derive(Debug, Error)]
 pub enum Error {
     /// Sqlite error
+    ///
+    /// When the database is locked by another connection, SQLite retries for up to
+    /// [`SQLiteOptions::busy_timeout`](crate::SQLiteOptions::busy_timeout) before returning
+    /// `SQLITE_BUSY`.
     #[error(transparent)]
-    Sqlite(#[from] rusqlite::Error),
-    /// Pool error
+    Sqlite(rusqlite::Error),
+    /// Sqlite error of a [`NostrDatabase`](nostr_database::NostrDatabase) operation, with the
+    /// name of the operation (i.e. `save_event`)
+    #[error("{operation}: {error}")]
+    Operation {
+        /// Name of the failed operation
+        operation: &'static str,
+        /// Sqlite error
+        error: rusqlite::Error,
+    },
+    /// Malformed database (`SQLITE_CORRUPT` or `SQLITE_NOTADB`)
+    ///
+    /// The database file is damaged or isn't a SQLite database: the events should be fetched
+    /// again from relays.
+    #[error("sqlite: malformed database: {0}")]
+    Malformed(rusqlite::Error),
+    /// I/O error
     #[error(transparent)]
-    CreateDeadPool(#[from] CreatePoolError),
-    /// Pool error
+    IO(#[from] std::io::Error),
+    /// Thread error
     #[error(transparent)]
-    DeadPool(#[from] PoolError),
-    /// Pool error
-    #[error("{0}")]
-    DeadPoolInteract(String),
+    Thread(#[from] JoinError),
     /// Migration error
     #[error(transparent)]
     Migration(#[from] MigrationError),
@@ -32,17 +49,73 @@ pub enum Error {
     /// Flatbuffers error
     #[error(transparent)]
     Flatbuffers(#[from] flatbuffers::Error),
+    /// Event error
+    #[error(transparent)]
+    Event(#[from] nostr::event::Error),
+    /// Event ID error
+    #[error(transparent)]
+    EventId(#[from] nostr::event::id::Error),
     /// Url error
     #[error(transparent)]
     Url(#[from] nostr::types::url::ParseError),
     /// Not found
     #[error("sqlite: {0} not found")]
     NotFound(String),
+    /// Unsupported operation (i.e. the required feature isn't enabled)
+    #[error("sqlite: unsupported: {0}")]
+    Unsupported(String),
+    /// Operation not completed within [`SQLiteOptions::timeout`](crate::SQLiteOptions::timeout)
+    #[error("sqlite: timeout")]
+    Timeout,
+    /// Database created by a newer version, with a schema not supported by this executable
+    #[error("sqlite: incompatible schema version v{found} (latest supported: v{supported}): upgrade this executable")]
+    IncompatibleSchema {
+        /// Schema version of the database
+        found: u32,
+        /// Latest schema version supported
+        supported: u32,
+    },
+    /// SQLite extension not loaded
+    #[cfg(feature = "load_extension")]
+    #[error("sqlite: can't load extension {}: {error}", path.display())]
+    LoadExtension {
+        /// Path of the extension
+        path: std::path::PathBuf,
+        /// SQLite error
+        error: rusqlite::Error,
+    },
+    /// Database not closed: still in use by other clones or running operations
+    #[error("sqlite: database still in use")]
+    StillInUse,
+    /// Database opened in read-only mode
+    #[error("sqlite: database opened in read-only mode")]
+    ReadOnly,
+    /// Event already stored (see [`ConflictMode::Error`](crate::ConflictMode::Error))
+    #[error("sqlite: event {0} already stored")]
+    Conflict(nostr::EventId),
+    /// Operation interrupted by [`SQLiteDatabase::cancel_all`](crate::SQLiteDatabase::cancel_all)
+    #[error("sqlite: operation interrupted")]
+    Interrupted,
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        match e.sqlite_error_code() {
+            Some(ErrorCode::DatabaseCorrupt) | Some(ErrorCode::NotADatabase) => Self::Malformed(e),
+            Some(ErrorCode::OperationInterrupted) => Self::Interrupted,
+            _ => Self::Sqlite(e),
+        }
+    }
 }
 
-impl From<InteractError> for Error {
-    fn from(e: InteractError) -> Self {
-        Self::DeadPoolInteract(e.to_string())
+impl Error {
+    /// Add the name of the failed operation to the Sqlite errors (the other errors, including
+    /// [`Error::Malformed`], are already explicit)
+    pub(crate) fn context(self, operation: &'static str) -> Self {
+        match self {
+            Self::Sqlite(error) => Self::Operation { operation, error },
+            e => e,
+        }
     }
 }
 
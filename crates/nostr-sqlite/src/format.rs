@@ -0,0 +1,68 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Serialization format of the stored events
+//!
+//! Like the compression, the format of each row is stored in the `format` column of the
+//! `events` table, so changing [`StorageFormat`] doesn't require rewriting the database.
+//! The flag also versions the encoding: if the FlatBuffers schema changes, the new encoding
+//! gets a new flag, while the old one is kept to decode (and re-encode, with
+//! [`SQLiteDatabase::reencode_events`](crate::SQLiteDatabase::reencode_events)) the rows
+//! written before.
+
+use nostr::{Event, JsonUtil};
+use nostr_database::{FlatBufferDecode, TempEvent};
+
+use crate::Error;
+
+/// FlatBuffers encoded value (current schema)
+pub(crate) const FLATBUFFER: u8 = 0;
+/// JSON serialized value
+pub(crate) const JSON: u8 = 1;
+
+/// Serialization format of the new events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum StorageFormat {
+    /// FlatBuffers (default): compact and fast to decode
+    #[default]
+    FlatBuffer,
+    /// JSON (NIP-01): larger and slower to decode, but every field of the event is kept as is
+    Json,
+}
+
+impl StorageFormat {
+    pub(crate) fn flag(&self) -> u8 {
+        match self {
+            Self::FlatBuffer => FLATBUFFER,
+            Self::Json => JSON,
+        }
+    }
+}
+
+/// Stored value decodable in all the formats
+pub(crate) trait Decode: Sized {
+    fn decode_format(buf: &[u8], format: u8) -> Result<Self, Error>;
+}
+
+impl Decode for Event {
+    fn decode_format(buf: &[u8], format: u8) -> Result<Self, Error> {
+        match format {
+            FLATBUFFER => Ok(Event::decode(buf)?),
+            JSON => Ok(Event::from_json(buf)?),
+            other => Err(Error::Unsupported(format!("storage format {other}"))),
+        }
+    }
+}
+
+impl Decode for TempEvent {
+    /// Only FlatBuffers: the JSON rows must be decoded as [`Event`]
+    fn decode_format(buf: &[u8], format: u8) -> Result<Self, Error> {
+        match format {
+            FLATBUFFER => Ok(TempEvent::decode(buf)?),
+            other => Err(Error::Unsupported(format!(
+                "storage format {other} for temporary events"
+            ))),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Full-text search (FTS5)
+
+use nostr::Event;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::migration;
+use crate::{decode_event, Error};
+
+/// Create the `events_fts` table, indexing the already stored events if it didn't exist
+///
+/// The table isn't part of the versioned schema, so a database used without the `fts` feature
+/// can be opened with it (and vice versa). The events that can't be decoded aren't indexed,
+/// unless `strict` (see [`migration::backfill`]).
+pub(crate) fn init(conn: &mut Connection, strict: bool) -> Result<(), Error> {
+    let exists: Option<i64> = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'events_fts';",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    if exists.is_none() {
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "CREATE VIRTUAL TABLE events_fts USING fts5(event_id UNINDEXED, content);",
+        )?;
+        {
+            let mut stmt =
+                tx.prepare("INSERT INTO events_fts (event_id, content) VALUES (?, ?);")?;
+            migration::backfill(&tx, strict, |_, event| {
+                stmt.execute((event.id().to_hex(), event.content()))?;
+                Ok(())
+            })?;
+        }
+        tx.commit()?;
+        tracing::info!("Full-text search index created");
+    }
+
+    Ok(())
+}
+
+/// Index the content of an event
+pub(crate) fn insert(conn: &Connection, event_id: &str, content: &str) -> Result<(), Error> {
+    let mut stmt =
+        conn.prepare_cached("INSERT INTO events_fts (event_id, content) VALUES (?, ?);")?;
+    stmt.execute((event_id, content))?;
+    Ok(())
+}
+
+/// Search events by content, best matches first
+pub(crate) fn search(conn: &Connection, query: &str, limit: usize) -> Result<Vec<Event>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT events.event, events.compression, events.format FROM events_fts JOIN events ON events.event_id = events_fts.event_id WHERE events_fts MATCH ? ORDER BY events_fts.rank LIMIT ?;",
+    )?;
+    let mut rows = stmt.query((query, limit))?;
+    let mut events: Vec<Event> = Vec::new();
+    while let Some(row) = rows.next()? {
+        events.push(decode_event(row.get(0)?, row.get(1)?, row.get(2)?)?);
+    }
+    Ok(events)
+}
@@ -4,42 +4,220 @@
 
 //! SQLite Storage backend for Nostr SDK
 
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "load_extension"), forbid(unsafe_code))]
+#![cfg_attr(feature = "load_extension", deny(unsafe_code))]
 #![warn(missing_docs)]
 #![warn(rustdoc::bare_urls)]
+#![cfg_attr(bench, feature(test))]
 
-use std::collections::{BTreeSet, HashSet};
-use std::path::Path;
+#[cfg(bench)]
+extern crate test;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::io::{BufRead, Write};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub extern crate nostr;
 pub extern crate nostr_database as database;
+pub extern crate rusqlite;
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Config, Object, Pool, Runtime};
 use nostr::nips::nip01::Coordinate;
-use nostr::{Event, EventId, Filter, Timestamp, Url};
+use nostr::serde_json;
+use nostr::{Event, EventId, Filter, JsonUtil, Kind, Timestamp, Url};
 use nostr_database::{
-    Backend, DatabaseIndexes, EventIndexResult, FlatBufferBuilder, FlatBufferDecode,
-    FlatBufferEncode, NostrDatabase, Order, TempEvent,
+    Backend, DatabaseIndexes, EventIndexResult, FlatBufferBuilder, FlatBufferEncode, NostrDatabase,
+    Order, TempEvent,
 };
+use rusqlite::backup::{Backup, StepResult};
 use rusqlite::config::DbConfig;
-use tokio::sync::RwLock;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, InterruptHandle, OpenFlags, Params, Statement};
+use tokio::sync::{broadcast, mpsc, oneshot, OnceCell, RwLock};
 
+mod compression;
 mod error;
+mod format;
+#[cfg(feature = "fts")]
+mod fts;
+mod metrics;
 mod migration;
+mod options;
+mod pool;
+mod stream;
+mod subscription;
+mod transaction;
 
 pub use self::error::Error;
-use self::migration::STARTUP_SQL;
+use self::format::Decode;
+pub use self::format::StorageFormat;
+use self::metrics::Metrics;
+pub use self::metrics::{MetricEvent, Operation};
+pub use self::migration::Migration;
+pub use self::options::{AutoVacuum, JournalMode, SQLiteOptions, Synchronous, TempStore};
+use self::pool::{Pool, WeakPool};
+pub use self::stream::EventStream;
+pub use self::subscription::Subscription;
+use self::subscription::NOTIFICATIONS_CAPACITY;
+pub use self::transaction::Transaction;
+use self::transaction::TransactionOperation;
 
 const BATCH_SIZE: usize = 100;
+const IMPORT_BATCH_SIZE: usize = 1000;
+/// Number of IDs bound to each `DELETE ... IN (...)` (below the SQLite limit of 999 parameters)
+const DELETE_BATCH_SIZE: usize = 500;
+const BACKUP_PAGES_PER_STEP: c_int = 1024;
+/// Pause between the steps of a backup (or while the source is busy), to let the writes proceed
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+/// Number of rows decoded and indexed at a time when building the indexes
+const LOAD_BATCH_SIZE: usize = 10_000;
+/// Max values of an `IN` list bound one per parameter (see [`in_list`])
+const MAX_IN_PARAMS: usize = 256;
+/// Approximate memory of an indexed event (entry, shared pointer and lookup tables), in bytes
+const INDEX_ENTRY_SIZE: usize = 200;
+/// Approximate memory of an indexed tag value, in bytes
+const INDEX_TAG_SIZE: usize = 32;
+
+/// Callback of [`SQLiteDatabase::open_with_progress`]
+type Progress<'a> = &'a (dyn Fn(usize, usize) + Send + Sync);
+
+/// Result of [`SQLiteDatabase::bulk_import_counted`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulkImportResult {
+    /// New events written to the database
+    pub stored: usize,
+    /// Already stored events deleted from the database, replaced or deleted (NIP-09) by the
+    /// imported ones
+    pub discarded: usize,
+    /// Imported events not written: duplicates, expired, ephemeral, deleted or replaced by a
+    /// newer event (already stored or of the same batch)
+    pub ignored: usize,
+    /// Events with an invalid ID or signature, not imported (only counted by
+    /// [`SQLiteDatabase::bulk_import_verified`])
+    pub rejected: usize,
+}
+
+/// Outcome of [`SQLiteDatabase::save_event_outcome`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SaveOutcome {
+    /// New event stored
+    Stored,
+    /// New replaceable (or parameterized replaceable) event stored, replacing an older one
+    Replaced,
+    /// Event not stored: ephemeral, expired, deleted or older than the stored replaceable one
+    Rejected,
+    /// Event already stored
+    AlreadyHave,
+}
+
+/// Behavior of [`SQLiteDatabase::save_event_with`] when the event is already stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ConflictMode {
+    /// Keep the stored event, returning [`SaveOutcome::AlreadyHave`] (default)
+    #[default]
+    Ignore,
+    /// Rewrite the stored event (with the current [`SQLiteOptions::storage_format`] and
+    /// compression), returning [`SaveOutcome::AlreadyHave`]
+    Replace,
+    /// Fail with [`Error::Conflict`]
+    Error,
+}
+
+/// Write-Ahead Log status, returned by [`SQLiteDatabase::wal_info`]
+///
+/// Returned only in WAL mode: the `Option` returned by [`SQLiteDatabase::wal_info`] is the only
+/// signal of the journal mode in use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalInfo {
+    /// Size of the `-wal` file in bytes (`0` if it doesn't exist yet)
+    ///
+    /// The file isn't shrunk by the automatic checkpoints, only by
+    /// [`SQLiteDatabase::checkpoint`].
+    pub size: u64,
+    /// Size over which the WAL is automatically checkpointed, in bytes (`0` if disabled)
+    pub autocheckpoint_size: u64,
+}
+
+/// Result of [`SQLiteDatabase::import_jsonl`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonlImport {
+    /// Valid events passed to [`NostrDatabase::bulk_import`] (including duplicates)
+    pub imported: usize,
+    /// Malformed lines or events with an invalid ID or signature
+    pub skipped: usize,
+}
+
+/// Write of a [`Transaction`], once resolved by the indexes
+enum TransactionStep {
+    Insert(Event, Vec<u8>),
+    Seen(EventId, Url),
+    /// Record the IDs deleted with NIP-09 (see [`insert_tombstones`])
+    Tombstone(Vec<EventId>),
+    Delete(Vec<EventId>),
+    DeleteAll,
+}
+
+/// Restores the configured [`Synchronous`] level at the end of [`SQLiteDatabase::with_synchronous`]
+///
+/// If dropped without calling [`SynchronousGuard::restore`] (i.e. a panic or a cancellation),
+/// the level is restored by a task spawned on the current runtime.
+struct SynchronousGuard {
+    pool: Option<Pool>,
+    level: Synchronous,
+}
+
+impl SynchronousGuard {
+    async fn restore(mut self) -> Result<(), Error> {
+        match self.pool.take() {
+            Some(pool) => set_synchronous(&pool, self.level).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SynchronousGuard {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let level: Synchronous = self.level;
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        if let Err(e) = set_synchronous(&pool, level).await {
+                            tracing::error!("Impossible to restore the synchronous level: {e}");
+                        }
+                    });
+                }
+                Err(_) => {
+                    tracing::error!("Impossible to restore the synchronous level: no runtime")
+                }
+            }
+        }
+    }
+}
 
 /// SQLite Nostr Database
 #[derive(Debug, Clone)]
 pub struct SQLiteDatabase {
-    db: Pool,
+    pool: Pool,
     indexes: DatabaseIndexes,
     fbb: Arc<RwLock<FlatBufferBuilder<'static>>>,
+    opts: SQLiteOptions,
+    metrics: Metrics,
+    notifications: broadcast::Sender<Event>,
+    path: Option<PathBuf>,
+    /// Set once the indexes have been built from the stored events
+    indexes_built: Arc<OnceCell<()>>,
+    /// Number of stored events, for [`SQLiteOptions::optimize_every`]
+    writes: Arc<AtomicU64>,
+    /// Number of inserted seen entries, for [`SQLiteOptions::max_seen_entries`]
+    seen_writes: Arc<AtomicU64>,
+    /// Timestamp of the last change to the stored events (`0` if none)
+    last_mutation: Arc<AtomicU64>,
 }
 
 impl SQLiteDatabase {
@@ -48,369 +226,6860 @@ impl SQLiteDatabase {
     where
         P: AsRef<Path>,
     {
-        let cfg = Config::new(path.as_ref());
-        let pool = cfg.create_pool(Runtime::Tokio1)?;
+        Self::open_with_opts(path, SQLiteOptions::default()).await
+    }
+
+    /// Open SQLite store with custom [`SQLiteOptions`]
+    pub async fn open_with_opts<P>(path: P, opts: SQLiteOptions) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with(path, opts, &[], None).await
+    }
 
-        // Execute migrations
-        let conn = pool.get().await?;
-        migration::run(&conn).await?;
+    /// Open SQLite store, calling `progress` with the number of loaded and total events while
+    /// building the indexes
+    ///
+    /// The events are decoded and indexed in batches, so `progress` is called after each batch
+    /// (and once at the end, also if the database is empty).
+    pub async fn open_with_progress<P, F>(path: P, progress: F) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        F: Fn(usize, usize) + Send + Sync,
+    {
+        Self::open_with(path, SQLiteOptions::default(), &[], Some(&progress)).await
+    }
 
-        let this = Self {
-            db: pool,
-            indexes: DatabaseIndexes::new(),
-            fbb: Arc::new(RwLock::new(FlatBufferBuilder::with_capacity(70_000))),
+    /// Open SQLite store, applying the application-specific [`Migration`]s after the built-in ones
+    ///
+    /// The custom schema version is tracked in a dedicated table, so it never collides with the
+    /// built-in one.
+    pub async fn open_with_migrations<P>(path: P, extra: &[Migration]) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with(path, SQLiteOptions::default(), extra, None).await
+    }
+
+    async fn open_with<P>(
+        path: P,
+        opts: SQLiteOptions,
+        migrations: &[Migration],
+        progress: Option<Progress<'_>>,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path: &Path = path.as_ref();
+        let conn = if opts.read_only {
+            open_reader(path)?
+        } else {
+            let created: bool = !path.exists();
+            let conn: Connection = Connection::open(path)?;
+            if created {
+                set_file_mode(path, opts.file_mode)?;
+            }
+            conn
         };
 
-        // Build indexes
-        this.build_indexes(&conn).await?;
+        let readers: Vec<Connection> = (0..opts.num_readers())
+            .map(|_| open_reader(path))
+            .collect::<Result<_, _>>()?;
 
-        Ok(this)
+        Self::new(
+            Some(path.to_path_buf()),
+            conn,
+            readers,
+            opts,
+            migrations,
+            progress,
+        )
+        .await
     }
 
-    async fn acquire(&self) -> Result<Object, Error> {
-        Ok(self.db.get().await?)
+    /// Open SQLite store in read-only mode
+    ///
+    /// See [`SQLiteOptions::read_only`] for details.
+    pub async fn open_read_only<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with_opts(path, SQLiteOptions::new().read_only(true)).await
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn build_indexes(&self, conn: &Object) -> Result<(), Error> {
-        let events = conn
-            .interact(move |conn| {
-                let mut stmt = conn.prepare_cached("SELECT event FROM events;")?;
-                let mut rows = stmt.query([])?;
-                let mut events = BTreeSet::new();
-                while let Ok(Some(row)) = rows.next() {
-                    let buf: Vec<u8> = row.get(0)?;
-                    let raw = TempEvent::decode(&buf)?;
-                    events.insert(raw);
-                }
-                Ok::<BTreeSet<TempEvent>, Error>(events)
-            })
-            .await??;
-
-        // Build indexes
-        let to_discard: Vec<EventId> = self.indexes.bulk_index(events).await.into_iter().collect();
-
-        // Discard events
-        if !to_discard.is_empty() {
-            let conn = self.acquire().await?;
-            conn.interact(move |conn| {
-                for chunk in to_discard.chunks(BATCH_SIZE) {
-                    let delete_query = format!(
-                        "DELETE FROM events WHERE {};",
-                        chunk
-                            .iter()
-                            .map(|id| format!("event_id = '{id}'"))
-                            .collect::<Vec<_>>()
-                            .join(" OR ")
-                    );
-                    conn.execute(&delete_query, [])?;
-                }
-
-                Ok::<(), Error>(())
-            })
-            .await??;
-        }
-        Ok(())
+    /// Open SQLite store without building the indexes
+    ///
+    /// Useful for ingesting events (`save_event`, `event_by_id`) without loading a large
+    /// database at startup. See [`SQLiteOptions::lazy_indexes`] for details.
+    pub async fn open_lazy<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with_opts(path, SQLiteOptions::new().lazy_indexes(true)).await
     }
-}
 
-#[async_trait]
-impl NostrDatabase for SQLiteDatabase {
-    type Err = Error;
+    /// Open in-memory SQLite store
+    ///
+    /// The pool holds a single connection (no read connections), so all the operations share the
+    /// same database.
+    /// Every call creates a new, independent database: opening in-memory twice gives two
+    /// databases that don't see each other's data.
+    pub async fn open_in_memory() -> Result<Self, Error> {
+        let conn = Connection::open_in_memory()?;
+        Self::new(None, conn, Vec::new(), SQLiteOptions::default(), &[], None).await
+    }
 
-    fn backend(&self) -> Backend {
-        Backend::SQLite
+    /// Open SQLite store from an already configured [`Connection`]
+    ///
+    /// Extension point for what [`SQLiteOptions`] doesn't cover (i.e. custom VFS, loadable
+    /// extensions, authorizer). The caller is responsible for having opened the connection with
+    /// the appropriate flags: it must be writable, unless [`SQLiteOptions::read_only`] is set.
+    /// The options are applied to it, then the migrations are run and the indexes built as in
+    /// [`SQLiteDatabase::open_with_opts`]. All the operations use this single connection
+    /// ([`SQLiteOptions::read_connections`] is ignored).
+    pub async fn from_connection(conn: Connection, opts: SQLiteOptions) -> Result<Self, Error> {
+        // Empty for in-memory and temporary databases
+        let path: Option<PathBuf> = conn
+            .path()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        Self::new(path, conn, Vec::new(), opts, &[], None).await
     }
 
-    #[tracing::instrument(skip_all, level = "trace")]
-    async fn save_event(&self, event: &Event) -> Result<bool, Self::Err> {
-        // Index event
-        let EventIndexResult {
-            to_store,
-            to_discard,
-        } = self.indexes.index_event(event).await;
-
-        if !to_discard.is_empty() {
-            let conn = self.acquire().await?;
-            let to_discard: Vec<EventId> = to_discard.into_iter().collect();
-            conn.interact(move |conn| {
-                for chunk in to_discard.chunks(BATCH_SIZE) {
-                    let delete_query = format!(
-                        "DELETE FROM events WHERE {};",
-                        chunk
-                            .iter()
-                            .map(|id| format!("event_id = '{id}'"))
-                            .collect::<Vec<_>>()
-                            .join(" OR ")
-                    );
-                    conn.execute(&delete_query, [])?;
-                }
+    async fn new(
+        path: Option<PathBuf>,
+        conn: Connection,
+        readers: Vec<Connection>,
+        opts: SQLiteOptions,
+        migrations: &[Migration],
+        progress: Option<Progress<'_>>,
+    ) -> Result<Self, Error> {
+        // Apply options (must be done before executing the migrations)
+        opts.apply(&conn)?;
+        for reader in readers.iter() {
+            opts.apply_to_reader(reader)?;
+        }
 
-                Ok::<(), Error>(())
+        // Execute migrations (or just check the schema version if read-only)
+        let pool = Pool::new(conn, readers, opts.timeout);
+        let migrations: Vec<Migration> = migrations.to_vec();
+        if opts.read_only {
+            pool.interact(move |conn| {
+                migration::check(conn)?;
+                migration::check_custom(conn, &migrations)
             })
             .await??;
-        }
-
-        if to_store {
-            // Acquire FlatBuffers Builder
-            let mut fbb = self.fbb.write().await;
-
-            // Encode
-            let event_id: EventId = event.id();
-            let value: Vec<u8> = event.encode(&mut fbb).to_vec();
-
-            // Save event
-            let conn = self.acquire().await?;
-            conn.interact(move |conn| {
-                let mut stmt = conn.prepare_cached(
-                    "INSERT OR IGNORE INTO events (event_id, event) VALUES (?, ?);",
-                )?;
-                stmt.execute((event_id.to_hex(), value))
+        } else {
+            let strict: bool = opts.strict_decode;
+            let opts: SQLiteOptions = opts.clone();
+            pool.interact(move |conn| {
+                migration::run(conn, strict)?;
+                migration::run_custom(conn, &migrations)?;
+                opts.apply_after_migrations(conn)
             })
             .await??;
 
-            Ok(true)
-        } else {
-            Ok(false)
+            #[cfg(feature = "fts")]
+            pool.interact(move |conn| fts::init(conn, strict)).await??;
         }
-    }
 
-    #[tracing::instrument(skip_all, level = "trace")]
-    async fn bulk_import(&self, events: BTreeSet<Event>) -> Result<(), Self::Err> {
-        // Acquire database conn lock
-        let conn = self.acquire().await?;
+        let this = Self {
+            pool,
+            indexes: DatabaseIndexes::new(),
+            fbb: Arc::new(RwLock::new(FlatBufferBuilder::with_capacity(
+                opts.flatbuffer_capacity,
+            ))),
+            opts,
+            metrics: Metrics::default(),
+            notifications: broadcast::channel(NOTIFICATIONS_CAPACITY).0,
+            path,
+            indexes_built: Arc::new(OnceCell::new()),
+            writes: Arc::new(AtomicU64::new(0)),
+            seen_writes: Arc::new(AtomicU64::new(0)),
+            last_mutation: Arc::new(AtomicU64::new(0)),
+        };
 
-        // Acquire FlatBuffers Builder
-        let mut fbb = self.fbb.write().await;
+        if let (Some(interval), false) = (this.opts.checkpoint_interval, this.opts.read_only) {
+            spawn_flush_task(this.pool.downgrade(), interval);
+        }
 
-        // Events to store
-        let events = self.indexes.bulk_import(events).await;
+        // Build indexes
+        if !this.opts.lazy_indexes {
+            this.build_indexes_with_progress(progress).await?;
+            this.set_indexes_built();
+        }
 
-        // Encode
-        let events: Vec<(EventId, Vec<u8>)> = events
-            .into_iter()
-            .map(move |e| {
-                let event_id: EventId = e.id();
-                let value: Vec<u8> = e.encode(&mut fbb).to_vec();
-                (event_id, value)
-            })
-            .collect();
+        Ok(this)
+    }
 
-        // Bulk save
-        conn.interact(move |conn| {
-            let tx = conn.transaction()?;
+    /// Query store with filters, streaming the events
+    ///
+    /// The matching IDs are resolved by the indexes up front, in the same order as
+    /// [`NostrDatabase::query`] ([`Order::Desc`]: newest first, [`Order::Asc`]: oldest first),
+    /// while the events are loaded and decoded lazily, in batches, as the stream is consumed.
+    pub async fn query_stream(&self, filters: Vec<Filter>, order: Order) -> EventStream {
+        let (tx, rx) = mpsc::channel(BATCH_SIZE);
+        if let Err(e) = self.ensure_indexes().await {
+            let _ = tx.send(Err(e)).await;
+            return EventStream::new(rx);
+        }
 
-            for (event_id, value) in events.into_iter() {
-                tx.execute(
-                    "INSERT OR IGNORE INTO events (event_id, event) VALUES (?, ?);",
-                    (event_id.to_hex(), value),
-                )?;
-            }
+        let ids: Vec<EventId> = self.indexes.query(filters, order).await;
+        let pool: Pool = self.pool.clone();
 
-            tx.commit()
-        })
-        .await??;
+        tokio::spawn(async move {
+            for chunk in ids.chunks(BATCH_SIZE) {
+                let chunk: Vec<EventId> = chunk.to_vec();
+                let events = match pool
+                    .interact_read(move |conn| fetch_events(conn, &chunk))
+                    .await
+                {
+                    Ok(Ok(events)) => events,
+                    Ok(Err(e)) | Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
 
-        Ok(())
+                for event in events.into_iter() {
+                    // Stop if the stream was dropped
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        EventStream::new(rx)
     }
 
-    async fn has_event_already_been_saved(&self, event_id: &EventId) -> Result<bool, Self::Err> {
-        if self.indexes.has_event_id_been_deleted(event_id).await {
-            Ok(true)
-        } else {
-            let conn = self.acquire().await?;
-            let event_id: String = event_id.to_hex();
-            conn.interact(move |conn| {
-                let mut stmt = conn.prepare_cached(
-                    "SELECT EXISTS(SELECT 1 FROM events WHERE event_id = ? LIMIT 1);",
-                )?;
-                let mut rows = stmt.query([event_id])?;
-                let exists: u8 = match rows.next()? {
-                    Some(row) => row.get(0)?,
-                    None => 0,
-                };
-                Ok(exists == 1)
-            })
-            .await?
-        }
+    /// Path of the database file (`None` if in-memory)
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
     }
 
-    async fn has_event_already_been_seen(&self, event_id: &EventId) -> Result<bool, Self::Err> {
-        let conn = self.acquire().await?;
-        let event_id: String = event_id.to_hex();
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare_cached(
-                "SELECT EXISTS(SELECT 1 FROM event_seen_by_relays WHERE event_id = ? LIMIT 1);",
-            )?;
-            let mut rows = stmt.query([event_id])?;
-            let exists: u8 = match rows.next()? {
-                Some(row) => row.get(0)?,
-                None => 0,
-            };
-            Ok(exists == 1)
-        })
-        .await?
+    /// Subscribe to the newly saved events matching `filter`
+    ///
+    /// The events are notified once committed by [`NostrDatabase::save_event`],
+    /// [`NostrDatabase::bulk_import`] (and the other methods saving events), in the order they are
+    /// saved. The `limit` and `search` of the filter are ignored. The already stored events
+    /// aren't notified: use [`NostrDatabase::query`] for them.
+    ///
+    /// Up to 1024 saved events are buffered for each subscription: if it's not polled fast
+    /// enough, the oldest ones are skipped (logging a warning) and it continues from the oldest
+    /// still buffered. The stream ends when all the clones of the database are dropped.
+    pub fn subscribe(&self, filter: Filter) -> Subscription {
+        Subscription::new(filter, self.notifications.subscribe())
     }
 
-    async fn has_event_id_been_deleted(&self, event_id: &EventId) -> Result<bool, Self::Err> {
-        Ok(self.indexes.has_event_id_been_deleted(event_id).await)
+    /// Notify the saved events to the subscribers (if any)
+    fn notify<'a, I>(&self, events: I)
+    where
+        I: IntoIterator<Item = &'a Event>,
+    {
+        if self.notifications.receiver_count() > 0 {
+            for event in events.into_iter() {
+                let _ = self.notifications.send(event.clone());
+            }
+        }
     }
 
-    async fn has_coordinate_been_deleted(
+    /// Query store with filters, returning a page of the results
+    ///
+    /// `offset` and `limit` are applied after filtering and ordering, so the results of
+    /// multiple filters are paginated as a single set.
+    /// Less than `limit` events are returned when the results are exhausted.
+    pub async fn query_paginated(
         &self,
-        coordinate: &Coordinate,
-        timestamp: Timestamp,
-    ) -> Result<bool, Self::Err> {
-        Ok(self
+        filters: Vec<Filter>,
+        order: Order,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<Event>, Error> {
+        self.ensure_indexes().await?;
+        let ids: Vec<EventId> = self
             .indexes
-            .has_coordinate_been_deleted(coordinate, timestamp)
-            .await)
-    }
+            .query(filters, order)
+            .await
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect();
 
-    async fn event_id_seen(&self, event_id: EventId, relay_url: Url) -> Result<(), Self::Err> {
-        let conn = self.acquire().await?;
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare_cached(
-                "INSERT OR IGNORE INTO event_seen_by_relays (event_id, relay_url) VALUES (?, ?);",
-            )?;
-            stmt.execute((event_id.to_hex(), relay_url.to_string()))
-        })
-        .await??;
-        Ok(())
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.pool
+            .interact_read(move |conn| {
+                let mut events: Vec<Event> = Vec::with_capacity(ids.len());
+                for chunk in ids.chunks(BATCH_SIZE) {
+                    events.extend(fetch_events(conn, chunk)?);
+                }
+                Ok(events)
+            })
+            .await?
     }
 
-    async fn event_seen_on_relays(
+    /// Query store with filters, returning only the events seen on `relay_url`
+    ///
+    /// The filters are restricted to the IDs seen on the relay before being evaluated, so they
+    /// have the same semantics of [`NostrDatabase::query`] (i.e. the `limit` applies to the
+    /// events of the relay).
+    pub async fn events_from_relay(
         &self,
-        event_id: EventId,
-    ) -> Result<Option<HashSet<Url>>, Self::Err> {
-        let conn = self.acquire().await?;
-        conn.interact(move |conn| {
-            let mut stmt = conn
-                .prepare_cached("SELECT relay_url FROM event_seen_by_relays WHERE event_id = ?;")?;
-            let mut rows = stmt.query([event_id.to_hex()])?;
-            let mut relays = HashSet::new();
-            while let Ok(Some(row)) = rows.next() {
-                let url: String = row.get(0)?;
-                relays.insert(Url::parse(&url)?);
-            }
-            Ok(Some(relays))
-        })
-        .await?
-    }
+        relay_url: &Url,
+        filters: Vec<Filter>,
+        order: Order,
+    ) -> Result<Vec<Event>, Error> {
+        let relay_url: String = relay_url.to_string();
+        let seen: HashSet<EventId> = self
+            .pool
+            .interact_read(move |conn| {
+                select_event_ids(
+                    conn,
+                    "SELECT event_id FROM event_seen_by_relays WHERE relay_url = ?;",
+                    [relay_url],
+                )
+            })
+            .await??
+            .into_iter()
+            .collect();
 
-    #[tracing::instrument(skip_all, level = "trace")]
-    async fn event_by_id(&self, event_id: EventId) -> Result<Event, Self::Err> {
-        let conn = self.acquire().await?;
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare_cached("SELECT event FROM events WHERE event_id = ?;")?;
-            let mut rows = stmt.query([event_id.to_hex()])?;
-            let row = rows
-                .next()?
-                .ok_or_else(|| Error::NotFound("event".into()))?;
-            let buf: Vec<u8> = row.get(0)?;
-            Ok(Event::decode(&buf)?)
-        })
-        .await?
-    }
+        if seen.is_empty() {
+            return Ok(Vec::new());
+        }
 
-    #[tracing::instrument(skip_all, level = "trace")]
-    async fn count(&self, filters: Vec<Filter>) -> Result<usize, Self::Err> {
-        Ok(self.indexes.count(filters).await)
-    }
+        // An empty set of IDs would match every event: skip the filter
+        let filters: Vec<Filter> = filters
+            .into_iter()
+            .filter_map(|mut filter| {
+                let ids: HashSet<EventId> = match filter.ids.take() {
+                    Some(ids) => ids.into_iter().filter(|id| seen.contains(id)).collect(),
+                    None => seen.clone(),
+                };
+                if ids.is_empty() {
+                    None
+                } else {
+                    Some(filter.ids(ids))
+                }
+            })
+            .collect();
 
-    #[tracing::instrument(skip_all, level = "trace")]
-    async fn query(&self, filters: Vec<Filter>, order: Order) -> Result<Vec<Event>, Self::Err> {
-        let conn = self.acquire().await?;
+        if filters.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.ensure_indexes().await?;
         let ids: Vec<EventId> = self.indexes.query(filters, order).await;
-        conn.interact(move |conn| {
-            let mut events = Vec::with_capacity(ids.len());
-            for chunk in ids.chunks(BATCH_SIZE) {
-                let mut stmt = conn.prepare_cached(&format!(
-                    "SELECT event FROM events WHERE {};",
-                    chunk
-                        .iter()
-                        .map(|id| format!("event_id = '{id}'"))
-                        .collect::<Vec<_>>()
-                        .join(" OR ")
-                ))?;
-                let mut rows = stmt.query([])?;
-                while let Ok(Some(row)) = rows.next() {
-                    let buf: Vec<u8> = row.get(0)?;
-                    events.push(Event::decode(&buf)?);
-                }
-            }
-            Ok(events)
-        })
-        .await?
+        self.events_by_ids(&ids).await
     }
 
-    async fn event_ids_by_filters(
+    /// Query store with filters, returning each event with the relays that have seen it
+    ///
+    /// The events are sorted like [`NostrDatabase::query`]. The events and their relays are
+    /// loaded in batches on the same connection, instead of calling
+    /// [`NostrDatabase::event_seen_on_relays`] for each event. Events never seen on a relay
+    /// have an empty set.
+    pub async fn query_with_relays(
         &self,
         filters: Vec<Filter>,
         order: Order,
-    ) -> Result<Vec<EventId>, Self::Err> {
-        Ok(self.indexes.query(filters, order).await)
+    ) -> Result<Vec<(Event, HashSet<Url>)>, Error> {
+        self.ensure_indexes().await?;
+        let ids: Vec<EventId> = self.indexes.query(filters, order).await;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.pool
+            .interact_read(move |conn| {
+                let mut results: Vec<(Event, HashSet<Url>)> = Vec::with_capacity(ids.len());
+                for chunk in ids.chunks(BATCH_SIZE) {
+                    let placeholders: String = vec!["?"; chunk.len()].join(",");
+                    let mut stmt = conn.prepare_cached(&format!(
+                        "SELECT event_id, relay_url FROM event_seen_by_relays WHERE event_id IN ({placeholders});"
+                    ))?;
+                    let mut rows =
+                        stmt.query(params_from_iter(chunk.iter().map(|id| id.to_hex())))?;
+                    let mut relays: HashMap<EventId, HashSet<Url>> = HashMap::new();
+                    while let Some(row) = rows.next()? {
+                        let event_id: String = row.get(0)?;
+                        let url: String = row.get(1)?;
+                        relays
+                            .entry(EventId::from_hex(event_id)?)
+                            .or_default()
+                            .insert(Url::parse(&url)?);
+                    }
+
+                    for event in fetch_events(conn, chunk)?.into_iter() {
+                        let seen: HashSet<Url> = relays.remove(&event.id()).unwrap_or_default();
+                        results.push((event, seen));
+                    }
+                }
+                Ok(results)
+            })
+            .await?
     }
 
-    async fn negentropy_items(
+    /// Query store with filters, failing with [`Error::Timeout`] if not completed within `timeout`
+    ///
+    /// Like [`NostrDatabase::query`], but bounds the evaluation of the filters by the indexes
+    /// (checked periodically) and the loading of the events (interrupted with
+    /// `sqlite3_interrupt`), so a single expensive filter can't hold a connection for long.
+    /// The wait for the indexes to be built (see [`SQLiteOptions::lazy_indexes`]) isn't bounded.
+    pub async fn query_with_timeout(
         &self,
-        filter: Filter,
-    ) -> Result<Vec<(EventId, Timestamp)>, Self::Err> {
-        Ok(self.indexes.negentropy_items(filter).await)
-    }
+        filters: Vec<Filter>,
+        order: Order,
+        timeout: Duration,
+    ) -> Result<Vec<Event>, Error> {
+        self.ensure_indexes().await?;
 
-    async fn delete(&self, filter: Filter) -> Result<(), Self::Err> {
-        match self.indexes.delete(filter).await {
-            Some(ids) => {
-                let conn = self.acquire().await?;
-                let ids: Vec<EventId> = ids.into_iter().collect();
-                conn.interact(move |conn| {
-                    for chunk in ids.chunks(BATCH_SIZE) {
-                        let delete_query = format!(
-                            "DELETE FROM events WHERE {};",
-                            chunk
-                                .iter()
-                                .map(|id| format!("event_id = '{id}'"))
-                                .collect::<Vec<_>>()
-                                .join(" OR ")
-                        );
-                        conn.execute(&delete_query, [])?;
+        let deadline: Instant = Instant::now() + timeout;
+        let prefilter: Option<(String, Vec<Value>)> = prefilter_condition(&filters);
+        let ids: Vec<EventId> = self
+            .indexes
+            .query_with_deadline(filters, order, deadline)
+            .await
+            .ok_or(Error::Timeout)?;
+        let strict: bool = self.opts.strict_decode;
+
+        let (tx, mut rx) = oneshot::channel::<InterruptHandle>();
+        let mut fut = Box::pin(self.pool.interact_read(move |conn| {
+            let _ = tx.send(conn.get_interrupt_handle());
+            load_events(conn, &ids, prefilter.as_ref(), strict)
+        }));
+
+        match tokio::time::timeout_at(deadline.into(), &mut fut).await {
+            Ok(res) => res?,
+            Err(_) => {
+                match rx.try_recv() {
+                    // Running: interrupt it and wait for the connection to be released
+                    Ok(handle) => {
+                        handle.interrupt();
+                        let _ = fut.await;
                     }
+                    // Not started yet (i.e. waiting for a connection)
+                    Err(_) => drop(fut),
+                }
+                Err(Error::Timeout)
+            }
+        }
+    }
 
-                    Ok::<(), Error>(())
+    /// Describe how [`NostrDatabase::query`] evaluates `filters` (with [`Order::Desc`]), for
+    /// debugging slow queries
+    ///
+    /// Returns the generated SQL statement followed by its `EXPLAIN QUERY PLAN`, one step per
+    /// line (indented by depth). The filters with a `search` are matched by the indexes instead:
+    /// in this case only the statement narrowing the rows loaded from the `events` table is
+    /// explained, if any.
+    pub async fn explain_query(&self, filters: Vec<Filter>) -> Result<String, Error> {
+        let (mut output, sql) = if !filters.is_empty() && filters.iter().all(is_sql_filter) {
+            let sql: (String, Vec<Value>) =
+                sorted_query(&filters, Order::Desc, self.opts.store_ephemeral);
+            (String::new(), Some(sql))
+        } else {
+            let output: String =
+                String::from("-- Matched by the indexes, then the events are loaded by ID\n");
+            let sql: Option<(String, Vec<Value>)> =
+                prefilter_condition(&filters).map(|(condition, params)| {
+                    let sql: String =
+                        format!("SELECT event, compression, format FROM events WHERE {condition};");
+                    (sql, params)
+                });
+            (output, sql)
+        };
+
+        if let Some((sql, params)) = sql {
+            let plan: String = self
+                .pool
+                .interact_read(move |conn| {
+                    let plan: String = explain(conn, &sql, &params)?;
+                    Ok::<String, Error>(format!("{sql}\n{plan}"))
                 })
                 .await??;
-            }
-            None => {
-                let conn = self.acquire().await?;
-                conn.interact(move |conn| conn.execute("DELETE FROM events;", []))
-                    .await??;
-            }
-        };
+            output.push_str(&plan);
+        }
 
-        Ok(())
+        Ok(output)
     }
 
-    async fn wipe(&self) -> Result<(), Self::Err> {
-        let conn = self.acquire().await?;
+    /// Like [`NostrDatabase::negentropy_items`], but writes the items into `items`
+    ///
+    /// The buffer is cleared first and its capacity reused, to avoid an allocation on every
+    /// pass of a reconciliation loop. On error it's left empty.
+    pub async fn negentropy_items_into(
+        &self,
+        filter: Filter,
+        items: &mut Vec<(EventId, Timestamp)>,
+    ) -> Result<(), Error> {
+        let buf: Vec<(EventId, Timestamp)> = std::mem::take(items);
+        *items = self
+            .metrics
+            .measure(
+                metrics::Operation::NegentropyItems,
+                self.load_negentropy_items(filter, buf),
+                |items| items.len(),
+            )
+            .await?;
+        Ok(())
+    }
 
-        conn.interact(|conn| {
-            // Reset DB
-            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, true)?;
-            conn.execute("VACUUM;", [])?;
-            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, false)?;
+    /// Load the negentropy items into `items` (cleared first)
+    async fn load_negentropy_items(
+        &self,
+        filter: Filter,
+        mut items: Vec<(EventId, Timestamp)>,
+    ) -> Result<Vec<(EventId, Timestamp)>, Error> {
+        items.clear();
 
-            // Execute migrations
-            conn.execute_batch(STARTUP_SQL)?;
+        // Search isn't stored in columns
+        if !is_sql_filter(&filter) {
+            self.ensure_indexes().await?;
+            items.extend(self.indexes.negentropy_items(filter).await);
+            return Ok(items);
+        }
 
-            Ok::<(), Error>(())
-        })
-        .await??;
+        let mut params: Vec<Value> = Vec::new();
+        let condition: String = match filter_condition(&filter, &mut params) {
+            Some(condition) => format!("WHERE {condition}"),
+            None => String::new(),
+        };
+        // Like the indexes, the limit keeps the newest events
+        let sql: String = match filter.limit {
+            Some(limit) => format!(
+                "SELECT event_id, created_at FROM (SELECT event_id, created_at FROM events {condition} ORDER BY created_at DESC LIMIT {limit}) ORDER BY created_at ASC, event_id ASC;"
+            ),
+            None => format!(
+                "SELECT event_id, created_at FROM events {condition} ORDER BY created_at ASC, event_id ASC;"
+            ),
+        };
 
-        migration::run(&conn).await?;
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare(&sql)?;
+                let mut rows = stmt.query(params_from_iter(params))?;
+                while let Some(row) = rows.next()? {
+                    let id: String = row.get(0)?;
+                    let created_at: u64 = row.get(1)?;
+                    items.push((EventId::from_hex(id)?, Timestamp::from(created_at)));
+                }
+                Ok(items)
+            })
+            .await?
+    }
 
-        self.indexes.clear().await;
+    /// Get all the events created between `since` and `until` (both inclusive), of any kind and
+    /// author
+    ///
+    /// The range is read with a single query on the `created_at` column, sorted like
+    /// [`NostrDatabase::query`] (the events with the same timestamp by ID), without going through
+    /// the indexes. The expired events not yet deleted are skipped. If `since` is after `until`,
+    /// no events are returned.
+    pub async fn events_in_range(
+        &self,
+        since: Timestamp,
+        until: Timestamp,
+        order: Order,
+    ) -> Result<Vec<Event>, Error> {
+        if since > until {
+            return Ok(Vec::new());
+        }
 
-        Ok(())
+        let direction: &str = match order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        let now: Timestamp = Timestamp::now();
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT event, compression, format FROM events WHERE created_at BETWEEN ? AND ? AND (expiration IS NULL OR expiration > ?) ORDER BY created_at {direction}, event_id ASC;"
+                ))?;
+                let mut rows = stmt.query((since.as_u64(), until.as_u64(), now.as_u64()))?;
+                let mut events: Vec<Event> = Vec::new();
+                while let Some(row) = rows.next()? {
+                    events.push(decode_event(row.get(0)?, row.get(1)?, row.get(2)?)?);
+                }
+                Ok(events)
+            })
+            .await?
+    }
+
+    /// Total number of events stored in the database
+    ///
+    /// Counted directly on the `events` table, without the indexes. It is executed on the same
+    /// connection used for writes, so it reflects every completed write or delete.
+    pub async fn len(&self) -> Result<usize, Error> {
+        self.pool
+            .interact(|conn| {
+                let count: usize =
+                    conn.query_row("SELECT COUNT(*) FROM events;", [], |row| row.get(0))?;
+                Ok(count)
+            })
+            .await?
+    }
+
+    /// Total length, in bytes, of the content of the stored events
+    pub async fn total_content_bytes(&self) -> Result<u64, Error> {
+        self.pool
+            .interact_read(|conn| {
+                let total: i64 = conn.query_row(
+                    "SELECT COALESCE(SUM(content_len), 0) FROM events;",
+                    [],
+                    |row| row.get(0),
+                )?;
+                Ok(total as u64)
+            })
+            .await?
+    }
+
+    /// Get the `n` stored events with the largest content, with its length in bytes
+    ///
+    /// Sorted by length (largest first), without decoding the events.
+    pub async fn largest_events(&self, n: usize) -> Result<Vec<(EventId, usize)>, Error> {
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT event_id, content_len FROM events ORDER BY content_len DESC, event_id ASC LIMIT ?;",
+                )?;
+                let mut rows = stmt.query([n as i64])?;
+                let mut events: Vec<(EventId, usize)> = Vec::new();
+                while let Some(row) = rows.next()? {
+                    let event_id: String = row.get(0)?;
+                    events.push((EventId::from_hex(event_id)?, row.get(1)?));
+                }
+                Ok(events)
+            })
+            .await?
+    }
+
+    /// Count the events that [`NostrDatabase::delete`] would remove with `filter`, without
+    /// deleting them
+    ///
+    /// Resolved like the deletion: by the indexes (including the `limit` and the replaceable
+    /// events already replaced, never counted) or, for an empty filter, counting every stored
+    /// event, since the whole table is cleared. A safe preview, as long as no events are saved
+    /// in the meantime.
+    pub async fn count_matching(&self, filter: Filter) -> Result<usize, Error> {
+        if filter.is_empty() {
+            return self.len().await;
+        }
+
+        self.ensure_indexes().await?;
+        Ok(self.indexes.count([filter]).await)
+    }
+
+    /// Check if there are no events stored in the database
+    pub async fn is_empty(&self) -> Result<bool, Error> {
+        self.pool
+            .interact(|conn| {
+                let exists: bool =
+                    conn.query_row("SELECT EXISTS(SELECT 1 FROM events LIMIT 1);", [], |row| {
+                        row.get(0)
+                    })?;
+                Ok(!exists)
+            })
+            .await?
+    }
+
+    /// Get [`Event`] by [`EventId`], returning `None` if it's not stored
+    ///
+    /// Unlike [`NostrDatabase::event_by_id`], errors are only returned for actual database or
+    /// decoding failures.
+    pub async fn event_by_id_opt(&self, event_id: EventId) -> Result<Option<Event>, Error> {
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT event, compression, format FROM events WHERE event_id = ?;",
+                )?;
+                let mut rows = stmt.query([event_id.to_hex()])?;
+                match rows.next()? {
+                    Some(row) => Ok(Some(decode_event(row.get(0)?, row.get(1)?, row.get(2)?)?)),
+                    None => Ok(None),
+                }
+            })
+            .await?
+    }
+
+    /// Get the stored value of the event, without decoding it (i.e. to forward it as is)
+    ///
+    /// The value is decompressed, but kept in the [`StorageFormat`] used when the event was
+    /// saved: FlatBuffers by default (decodable with
+    /// [`FlatBufferDecode`](nostr_database::FlatBufferDecode)), or JSON (NIP-01) with
+    /// [`StorageFormat::Json`]. If the format was changed, rewrite the older events with
+    /// [`SQLiteDatabase::reencode_events`] to get the same format for all of them.
+    pub async fn event_bytes_by_id(&self, event_id: EventId) -> Result<Option<Vec<u8>>, Error> {
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn
+                    .prepare_cached("SELECT event, compression FROM events WHERE event_id = ?;")?;
+                let mut rows = stmt.query([event_id.to_hex()])?;
+                match rows.next()? {
+                    Some(row) => Ok(Some(compression::decompress(row.get(0)?, row.get(1)?)?)),
+                    None => Ok(None),
+                }
+            })
+            .await?
+    }
+
+    /// Check if the event is in the `events` table
+    ///
+    /// Unlike [`NostrDatabase::has_event_already_been_saved`], the deleted events (NIP-09) aren't
+    /// reported as saved: only the stored rows are checked. It is executed on the same
+    /// connection used for writes, so it reflects every completed write or delete.
+    pub async fn is_stored(&self, event_id: EventId) -> Result<bool, Error> {
+        self.pool
+            .interact(move |conn| event_exists(conn, &event_id))
+            .await?
+    }
+
+    /// Get the current event of a replaceable or parameterized replaceable [`Coordinate`]
+    ///
+    /// Looked up in the coordinates table, kept up to date on every insert and delete, so the
+    /// indexes aren't needed. The `relays` of the coordinate are ignored. Returns `None` if the
+    /// current event has expired (NIP-40).
+    pub async fn event_by_coordinate(
+        &self,
+        coordinate: &Coordinate,
+    ) -> Result<Option<Event>, Error> {
+        let kind: u32 = coordinate.kind.as_u32();
+        let author: String = coordinate.public_key.to_hex();
+        let identifier: String = coordinate.identifier.clone();
+        let now: u64 = Timestamp::now().as_u64();
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT events.event, events.compression, events.format FROM event_coordinates JOIN events ON events.event_id = event_coordinates.event_id WHERE event_coordinates.kind = ? AND event_coordinates.author = ? AND event_coordinates.identifier = ? AND (events.expiration IS NULL OR events.expiration > ?);",
+                )?;
+                let mut rows = stmt.query((kind, author, identifier, now))?;
+                match rows.next()? {
+                    Some(row) => Ok(Some(decode_event(row.get(0)?, row.get(1)?, row.get(2)?)?)),
+                    None => Ok(None),
+                }
+            })
+            .await?
+    }
+
+    /// Get the latest event of a NIP-33 [`Coordinate`] (kind, author and `d` tag), i.e. to
+    /// resolve the `a` tags
+    ///
+    /// Same as [`SQLiteDatabase::event_by_coordinate`]: the expired events (NIP-40) aren't
+    /// returned, even if not deleted yet.
+    pub async fn latest_event_for_coordinate(
+        &self,
+        coordinate: &Coordinate,
+    ) -> Result<Option<Event>, Error> {
+        self.event_by_coordinate(coordinate).await
+    }
+
+    /// Get the SQLite rowid of the event (`None` if not stored)
+    ///
+    /// Rowids increase with the insertion order but, like the order of the events table, aren't
+    /// stable across `VACUUM`. Meant for debugging.
+    pub async fn event_rowid(&self, event_id: EventId) -> Result<Option<i64>, Error> {
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt =
+                    conn.prepare_cached("SELECT rowid FROM events WHERE event_id = ?;")?;
+                let mut rows = stmt.query([event_id.to_hex()])?;
+                match rows.next()? {
+                    Some(row) => Ok(Some(row.get(0)?)),
+                    None => Ok(None),
+                }
+            })
+            .await?
+    }
+
+    /// Get all the stored events with their rowid, in insertion order ([`Order::Asc`]: oldest
+    /// inserted first)
+    ///
+    /// Reads the events table directly, bypassing the indexes. Meant for debugging: all the
+    /// events are loaded in memory.
+    pub async fn events_by_rowid(&self, order: Order) -> Result<Vec<(i64, Event)>, Error> {
+        let direction: &str = match order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT rowid, event, compression, format FROM events ORDER BY rowid {direction};"
+                ))?;
+                let mut rows = stmt.query([])?;
+                let mut events: Vec<(i64, Event)> = Vec::new();
+                while let Some(row) = rows.next()? {
+                    events.push((row.get(0)?, decode_event(row.get(1)?, row.get(2)?, row.get(3)?)?));
+                }
+                Ok(events)
+            })
+            .await?
+    }
+
+    /// Get the stored events by [`EventId`], in the same order of `ids`
+    ///
+    /// The events are fetched in batches, with a single query each, on the same connection.
+    /// Missing events are skipped.
+    pub async fn events_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, Error> {
+        let ids: Vec<EventId> = ids.to_vec();
+        self.pool
+            .interact_read(move |conn| {
+                let mut events: Vec<Event> = Vec::with_capacity(ids.len());
+                for chunk in ids.chunks(BATCH_SIZE) {
+                    events.extend(fetch_events(conn, chunk)?);
+                }
+                Ok(events)
+            })
+            .await?
+    }
+
+    /// Set multiple [`EventId`]s as seen by relays, in a single transaction
+    ///
+    /// Like [`NostrDatabase::event_id_seen`], the pairs already stored are ignored.
+    pub async fn event_ids_seen(&self, entries: &[(EventId, Url)]) -> Result<(), Error> {
+        self.check_writable()?;
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let entries: Vec<(EventId, Url)> = entries.to_vec();
+        let len: usize = entries.len();
+        self.pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                for (event_id, relay_url) in entries.iter() {
+                    insert_seen(&tx, event_id, relay_url)?;
+                }
+                tx.commit()?;
+                Ok::<(), Error>(())
+            })
+            .await??;
+        self.count_seen(len).await
+    }
+
+    /// Get the IDs of all the stored events, in insertion order
+    ///
+    /// Read from the `event_id` column, without decoding the events nor using the indexes: also
+    /// includes the expired events not deleted yet and, if [`SQLiteOptions::store_ephemeral`]
+    /// is enabled, the ephemeral ones.
+    pub async fn all_event_ids(&self) -> Result<Vec<EventId>, Error> {
+        self.pool
+            .interact_read(|conn| {
+                select_event_ids(conn, "SELECT event_id FROM events ORDER BY rowid ASC;", ())
+            })
+            .await?
+    }
+
+    /// Get the IDs of the deleted events, persisted across restarts
+    ///
+    /// Only the NIP-09 deletions are persisted: the events deleted by a received or saved
+    /// deletion event, or with [`SQLiteDatabase::delete_by_ids`], also if never stored, until the
+    /// whole database is cleared. The events replaced, deleted by filter or by the retention (i.e.
+    /// expired) aren't included: the indexes treat them as deleted in memory only, so after a
+    /// restart they can be stored again if received.
+    pub async fn deleted_ids(&self) -> Result<HashSet<EventId>, Error> {
+        let ids: Vec<EventId> = self
+            .pool
+            .interact_read(|conn| {
+                select_event_ids(conn, "SELECT event_id FROM deleted_events;", ())
+            })
+            .await??;
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Get the subset of `ids` already saved (or deleted), answering
+    /// [`NostrDatabase::has_event_already_been_saved`] for many IDs at once
+    ///
+    /// The deleted IDs are included, so that they aren't requested again. The stored ones are
+    /// looked up in batches, with a single query each.
+    pub async fn which_are_saved(&self, ids: &[EventId]) -> Result<HashSet<EventId>, Error> {
+        self.ensure_indexes().await?;
+        let mut saved: HashSet<EventId> = HashSet::new();
+        let mut to_check: Vec<EventId> = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            if self.indexes.has_event_id_been_deleted(id).await {
+                saved.insert(*id);
+            } else {
+                to_check.push(*id);
+            }
+        }
+
+        if to_check.is_empty() {
+            return Ok(saved);
+        }
+
+        let stored: Vec<EventId> = self
+            .pool
+            .interact_read(move |conn| {
+                let mut stored: Vec<EventId> = Vec::new();
+                for chunk in to_check.chunks(BATCH_SIZE) {
+                    let placeholders: String = vec!["?"; chunk.len()].join(",");
+                    stored.extend(select_event_ids(
+                        conn,
+                        &format!("SELECT event_id FROM events WHERE event_id IN ({placeholders});"),
+                        params_from_iter(chunk.iter().map(|id| id.to_hex())),
+                    )?);
+                }
+                Ok::<Vec<EventId>, Error>(stored)
+            })
+            .await??;
+        saved.extend(stored);
+        Ok(saved)
+    }
+
+    /// Get the subset of `ids` seen by at least one relay, answering
+    /// [`NostrDatabase::has_event_already_been_seen`] for many IDs at once
+    ///
+    /// Looked up in batches, with a single query each.
+    pub async fn which_are_seen(&self, ids: &[EventId]) -> Result<HashSet<EventId>, Error> {
+        if ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let ids: Vec<EventId> = ids.to_vec();
+        self.pool
+            .interact_read(move |conn| {
+                let mut seen: HashSet<EventId> = HashSet::new();
+                for chunk in ids.chunks(BATCH_SIZE) {
+                    let placeholders: String = vec!["?"; chunk.len()].join(",");
+                    seen.extend(select_event_ids(
+                        conn,
+                        &format!("SELECT DISTINCT event_id FROM event_seen_by_relays WHERE event_id IN ({placeholders});"),
+                        params_from_iter(chunk.iter().map(|id| id.to_hex())),
+                    )?);
+                }
+                Ok(seen)
+            })
+            .await?
+    }
+
+    /// Number of distinct relays that have seen the event
+    pub async fn relay_count_for(&self, event_id: EventId) -> Result<usize, Error> {
+        self.pool
+            .interact_read(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT COUNT(*) FROM event_seen_by_relays WHERE event_id = ?;",
+                )?;
+                let count: usize = stmt.query_row([event_id.to_hex()], |row| row.get(0))?;
+                Ok(count)
+            })
+            .await?
+    }
+
+    /// Number of distinct relays that have seen each of the events (`0` if never seen)
+    pub async fn relay_counts(&self, ids: &[EventId]) -> Result<HashMap<EventId, usize>, Error> {
+        let ids: Vec<EventId> = ids.to_vec();
+        self.pool
+            .interact_read(move |conn| {
+                let mut counts: HashMap<EventId, usize> =
+                    ids.iter().map(|id| (*id, 0)).collect();
+                for chunk in ids.chunks(BATCH_SIZE) {
+                    let placeholders: String = vec!["?"; chunk.len()].join(",");
+                    let mut stmt = conn.prepare_cached(&format!(
+                        "SELECT event_id, COUNT(*) FROM event_seen_by_relays WHERE event_id IN ({placeholders}) GROUP BY event_id;"
+                    ))?;
+                    let mut rows = stmt.query(params_from_iter(chunk.iter().map(|id| id.to_hex())))?;
+                    while let Some(row) = rows.next()? {
+                        let event_id: String = row.get(0)?;
+                        counts.insert(EventId::from_hex(event_id)?, row.get(1)?);
+                    }
+                }
+                Ok(counts)
+            })
+            .await?
+    }
+
+    /// Number of stored events by [`Kind`]
+    ///
+    /// Grouped on the `kind` column, so the events aren't decoded.
+    pub async fn count_by_kind(&self) -> Result<BTreeMap<Kind, usize>, Error> {
+        self.pool
+            .interact_read(|conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT kind, COUNT(*) FROM events WHERE kind IS NOT NULL GROUP BY kind;",
+                )?;
+                let mut rows = stmt.query([])?;
+                let mut counts: BTreeMap<Kind, usize> = BTreeMap::new();
+                while let Some(row) = rows.next()? {
+                    let kind: u64 = row.get(0)?;
+                    counts.insert(Kind::from(kind), row.get(1)?);
+                }
+                Ok(counts)
+            })
+            .await?
+    }
+
+    /// Number of events in the indexes
+    ///
+    /// Lower than [`SQLiteDatabase::len`] with stored ephemeral events
+    /// ([`SQLiteOptions::store_ephemeral`]) or if the indexes aren't built yet
+    /// ([`SQLiteOptions::lazy_indexes`]).
+    pub async fn indexed_event_count(&self) -> usize {
+        self.indexes.count([Filter::new()]).await
+    }
+
+    /// Approximate memory used by the indexes, in bytes
+    ///
+    /// Estimated from the number of indexed events and of their single-letter tags, with the
+    /// average size of the entries: meant for capacity planning, not accounting.
+    pub async fn index_memory_estimate(&self) -> Result<usize, Error> {
+        let events: usize = self.indexed_event_count().await;
+        if events == 0 {
+            return Ok(0);
+        }
+
+        let tags: usize = self
+            .pool
+            .interact_read(|conn| {
+                let count: usize =
+                    conn.query_row("SELECT COUNT(*) FROM event_tags;", [], |row| row.get(0))?;
+                Ok::<_, Error>(count)
+            })
+            .await??;
+        Ok(events * INDEX_ENTRY_SIZE + tags * INDEX_TAG_SIZE)
+    }
+
+    /// Size of the database in bytes (`page_count * page_size`)
+    ///
+    /// Includes the pages written to the WAL but not yet checkpointed into the main file.
+    pub async fn database_size(&self) -> Result<u64, Error> {
+        self.pool
+            .interact(|conn| {
+                let page_count: u64 =
+                    conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+                let page_size: u64 =
+                    conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+                Ok(page_count * page_size)
+            })
+            .await?
+    }
+
+    /// Status of the Write-Ahead Log, or `None` if the journal mode isn't WAL
+    ///
+    /// The journal mode is read from the database, so `None` also means that
+    /// [`SQLiteOptions::journal_mode`] wasn't applied (i.e. WAL isn't supported by the
+    /// filesystem). In-memory databases always return `None`.
+    pub async fn wal_info(&self) -> Result<Option<WalInfo>, Error> {
+        let path: Option<PathBuf> = self.path.clone();
+        self.pool
+            .interact(move |conn| {
+                let mode: String =
+                    conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+                let path: PathBuf = match path {
+                    Some(path) if mode.eq_ignore_ascii_case("wal") => path,
+                    _ => return Ok(None),
+                };
+
+                let mut wal = path.into_os_string();
+                wal.push("-wal");
+                let size: u64 = match std::fs::metadata(wal) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(Error::IO(e)),
+                };
+                let pages: u64 =
+                    conn.pragma_query_value(None, "wal_autocheckpoint", |row| row.get(0))?;
+                let page_size: u64 =
+                    conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+                Ok(Some(WalInfo {
+                    size,
+                    autocheckpoint_size: pages * page_size,
+                }))
+            })
+            .await?
+    }
+
+    /// Number of unused pages in the database file
+    ///
+    /// These pages are reclaimed by `VACUUM`.
+    pub async fn freelist_count(&self) -> Result<u64, Error> {
+        self.pool
+            .interact(|conn| {
+                let count: u64 =
+                    conn.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+                Ok(count)
+            })
+            .await?
+    }
+
+    /// Rebuild the database file, reclaiming the unused pages (i.e. after a large `delete`)
+    ///
+    /// Unlike [`NostrDatabase::wipe`], the stored events and the indexes are left untouched.
+    /// `VACUUM` can't run inside a transaction and rewrites the whole file, so it may take a while
+    /// (and temporarily use up to twice the disk space) on large databases.
+    pub async fn vacuum(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.pool
+            .interact(|conn| conn.execute_batch("VACUUM;"))
+            .await??;
+        Ok(())
+    }
+
+    /// Change the page size of an existing database, rewriting it with `VACUUM`
+    ///
+    /// The page size of a WAL database can't be changed: the journal mode is temporarily
+    /// switched to `DELETE` (failing with `SQLITE_BUSY` if other processes have the database
+    /// open) and restored after the `VACUUM`. Like [`SQLiteDatabase::vacuum`], it may take a
+    /// while on large databases. Invalid sizes are ignored by SQLite.
+    pub async fn set_page_size(&self, bytes: u32) -> Result<(), Error> {
+        self.check_writable()?;
+        self.pool
+            .interact(move |conn| {
+                let mode: String =
+                    conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+                let wal: bool = mode.eq_ignore_ascii_case("wal");
+                if wal {
+                    conn.pragma_update(None, "journal_mode", "DELETE")?;
+                }
+                conn.pragma_update(None, "page_size", bytes)?;
+                let res = conn.execute_batch("VACUUM;");
+                if wal {
+                    conn.pragma_update(None, "journal_mode", "WAL")?;
+                }
+                Ok::<(), Error>(res?)
+            })
+            .await?
+    }
+
+    /// Rewrite the stored events not encoded with the current [`SQLiteOptions::storage_format`]
+    /// and compression level (i.e. written before changing them, or by an older
+    /// version with a previous FlatBuffers schema)
+    ///
+    /// The rows are updated in place, in batches committed separately, so it can be
+    /// interrupted and run again. Returns the number of rewritten events.
+    pub async fn reencode_events(&self) -> Result<usize, Error> {
+        self.check_writable()?;
+
+        let format: StorageFormat = self.opts.storage_format;
+        let level: Option<i32> = self.opts.compression;
+        let capacity: usize = self.opts.flatbuffer_capacity;
+        let condition: String = format!(
+            "format != {} OR compression != {}",
+            format.flag(),
+            compression::flag(level)
+        );
+        let rewritten: usize = self
+            .pool
+            .interact(move |conn| {
+                // Dedicated builder: the shared one can't be locked on a blocking thread
+                let mut fbb = FlatBufferBuilder::with_capacity(capacity);
+                migration::reencode(conn, &condition, |event| {
+                    let value: Vec<u8> = encode_event(&mut fbb, event, capacity, format);
+                    let (value, compression) = compression::compress(value, level)?;
+                    Ok((value, compression, format.flag()))
+                })
+            })
+            .await??;
+        if rewritten > 0 {
+            self.touch();
+        }
+        Ok(rewritten)
+    }
+
+    /// Release up to `pages` free pages to the filesystem (all of them if `None`)
+    ///
+    /// Has effect only with [`AutoVacuum::Incremental`]. Unlike [`SQLiteDatabase::vacuum`], the
+    /// file isn't rewritten, so it can be run often (i.e. after each cleanup) in small steps.
+    /// `Some(0)` releases nothing (`PRAGMA incremental_vacuum(0)` would release all the pages).
+    pub async fn incremental_vacuum(&self, pages: Option<u32>) -> Result<(), Error> {
+        self.check_writable()?;
+        let pages: u32 = match pages {
+            Some(0) => return Ok(()),
+            Some(pages) => pages,
+            None => 0,
+        };
+        self.pool
+            .interact(move |conn| {
+                // A page is released on every step
+                let mut stmt = conn.prepare(&format!("PRAGMA incremental_vacuum({pages});"))?;
+                let mut rows = stmt.query([])?;
+                while rows.next()?.is_some() {}
+                Ok::<(), Error>(())
+            })
+            .await??;
+        Ok(())
+    }
+
+    /// Checkpoint the WAL into the database file and truncate it (`PRAGMA wal_checkpoint(TRUNCATE)`)
+    ///
+    /// Waits for the running reads (up to [`SQLiteOptions::busy_timeout`]), while new writes are
+    /// blocked meanwhile. If some reads don't complete in time, the WAL is only partially
+    /// checkpointed and not truncated. Does nothing if the journal mode isn't WAL.
+    pub async fn checkpoint(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.pool.interact(|conn| checkpoint(conn, None)).await?
+    }
+
+    /// Flush the WAL into the database file, without blocking (`PRAGMA wal_checkpoint(PASSIVE)`)
+    ///
+    /// Since the database is shared between its clones, nothing is done when a handle is
+    /// dropped: call it (or [`SQLiteDatabase::close`]) before exiting, so that a restart doesn't
+    /// have to replay a large WAL. The frames still needed by the running reads are left in the
+    /// WAL. See also [`SQLiteOptions::checkpoint_interval`]. Does nothing if the journal mode
+    /// isn't WAL.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.pool.interact(|conn| flush(conn)).await?
+    }
+
+    /// Clear the indexes and build them again from the events table
+    ///
+    /// Recovers from changes made to the database outside of this instance (i.e. rows deleted
+    /// with another connection or by another process), that the indexes don't know about.
+    pub async fn reindex(&self) -> Result<(), Error> {
+        self.rebuild_indexes().await
+    }
+
+    /// Update the statistics used by the query planner (`PRAGMA optimize`)
+    ///
+    /// Cheap when the statistics are already up to date. Run on [`SQLiteDatabase::close`] and,
+    /// if set, every [`SQLiteOptions::optimize_every`] stored events, or schedule it periodically
+    /// for long-running processes.
+    pub async fn optimize(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.pool
+            .interact(|conn| {
+                conn.execute_batch("PRAGMA optimize;")?;
+                Ok(())
+            })
+            .await?
+    }
+
+    /// Time of the last change to the stored events made by this instance (or its clones), if any
+    ///
+    /// Updated when events are saved or deleted. The changes made by other processes aren't
+    /// tracked: see [`SQLiteDatabase::data_version`].
+    pub fn last_mutation(&self) -> Option<Timestamp> {
+        match self.last_mutation.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(Timestamp::from(secs)),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_mutation
+            .store(Timestamp::now().as_u64(), Ordering::Relaxed);
+    }
+
+    /// SQLite data version (`PRAGMA data_version`)
+    ///
+    /// Changes when the database is modified by another connection (i.e. another process), not
+    /// by this instance: compare it with the previous value to detect the external changes
+    /// (and use [`SQLiteDatabase::reindex`] to reload them).
+    pub async fn data_version(&self) -> Result<i64, Error> {
+        // The value is per connection: always read it from the writer
+        self.pool
+            .interact(|conn| {
+                let version: i64 =
+                    conn.pragma_query_value(None, "data_version", |row| row.get(0))?;
+                Ok(version)
+            })
+            .await?
+    }
+
+    /// Count the stored events, running [`SQLiteDatabase::optimize`] every
+    /// [`SQLiteOptions::optimize_every`] writes
+    async fn count_writes(&self, num: usize) -> Result<(), Error> {
+        let every: u64 = match self.opts.optimize_every {
+            Some(every) if num > 0 => every,
+            _ => return Ok(()),
+        };
+
+        let prev: u64 = self.writes.fetch_add(num as u64, Ordering::Relaxed);
+        if (prev + num as u64) / every > prev / every {
+            self.optimize().await?;
+        }
+        Ok(())
+    }
+
+    /// Count the inserted seen entries, running [`SQLiteDatabase::trim_seen`] every 10% of
+    /// [`SQLiteOptions::max_seen_entries`]
+    async fn count_seen(&self, num: usize) -> Result<(), Error> {
+        let max: usize = match self.opts.max_seen_entries {
+            Some(max) if num > 0 => max,
+            _ => return Ok(()),
+        };
+
+        let every: u64 = (max as u64 / 10).max(1);
+        let prev: u64 = self.seen_writes.fetch_add(num as u64, Ordering::Relaxed);
+        if (prev + num as u64) / every > prev / every {
+            let removed: usize = self.trim_seen(max).await?;
+            if removed > 0 {
+                tracing::debug!("Trimmed {removed} seen entries");
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the oldest entries of the relays that have seen the events, keeping the
+    /// `keep_recent` most recent ones
+    ///
+    /// Returns the number of removed entries. The entries are ordered by the time they have
+    /// been first seen (and by insertion for the ones stored before it was tracked). The
+    /// events aren't touched.
+    pub async fn trim_seen(&self, keep_recent: usize) -> Result<usize, Error> {
+        self.check_writable()?;
+        self.pool
+            .interact(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "DELETE FROM event_seen_by_relays WHERE id IN (SELECT id FROM event_seen_by_relays ORDER BY seen_at DESC, id DESC LIMIT -1 OFFSET ?);",
+                )?;
+                Ok(stmt.execute([keep_recent as i64])?)
+            })
+            .await?
+    }
+
+    /// Abort the SQL statements running on all the connections (i.e. for a prompt shutdown)
+    ///
+    /// The interrupted operations fail with [`Error::Interrupted`]; the ones waiting for a
+    /// connection, or started later, aren't affected.
+    pub fn cancel_all(&self) {
+        self.pool.interrupt_all();
+    }
+
+    /// Close the database, releasing the file
+    ///
+    /// The WAL is checkpointed (and truncated), `PRAGMA optimize` is run and all the connections
+    /// are closed (read-only databases are only closed). Returns [`Error::StillInUse`] if other
+    /// clones of the database (or query streams) are alive, or if an operation that timed out
+    /// (see [`SQLiteOptions::timeout`]) is still running on one of the connections: this handle
+    /// is dropped anyway, and the connections are closed when the last one is dropped, without
+    /// the checkpoint.
+    pub async fn close(self) -> Result<(), Error> {
+        let read_only: bool = self.opts.read_only;
+        self.pool
+            .close(move |conn| {
+                if !read_only {
+                    checkpoint(conn, None)?;
+                    conn.execute_batch("PRAGMA optimize;")?;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Run `PRAGMA integrity_check`, returning the list of problems found
+    ///
+    /// A single `"ok"` entry means that the database is healthy.
+    /// If the file is too damaged to even run the check, [`Error::Malformed`] is returned.
+    pub async fn integrity_check(&self) -> Result<Vec<String>, Error> {
+        self.pool
+            .interact(|conn| check_pragma(conn, "integrity_check"))
+            .await?
+    }
+
+    /// Run `PRAGMA quick_check`
+    ///
+    /// Faster than [`SQLiteDatabase::integrity_check`] (`O(N)` instead of `O(N log N)`), but
+    /// doesn't verify that the indexes match the table content.
+    pub async fn quick_check(&self) -> Result<Vec<String>, Error> {
+        self.pool
+            .interact(|conn| check_pragma(conn, "quick_check"))
+            .await?
+    }
+
+    /// Check that all the tables and indexes created by the migrations exist, returning the
+    /// missing ones (empty if the schema is complete)
+    ///
+    /// The missing indexes are recreated when the database is opened in read-write mode, so
+    /// they're reported only if dropped afterwards (or with a read-only database).
+    pub async fn verify_schema(&self) -> Result<Vec<String>, Error> {
+        self.pool
+            .interact_read(|conn| migration::verify(conn))
+            .await?
+    }
+
+    /// Schema version of the database (`PRAGMA user_version`)
+    ///
+    /// After opening it's always the latest version supported by this executable: databases
+    /// created by a newer version are rejected with [`Error::IncompatibleSchema`].
+    pub async fn schema_version(&self) -> Result<u32, Error> {
+        let version: usize = self.pool.interact(migration::curr_db_version).await??;
+        Ok(version as u32)
+    }
+
+    /// Run `f` with a different [`Synchronous`] level, restoring the configured one afterwards
+    ///
+    /// Useful to speed up large imports, trading durability: a crash during `f` may lose (or,
+    /// with [`Synchronous::Off`], corrupt) the data written meanwhile. The level applies to the
+    /// whole database, so also to the writes made by other tasks while `f` is running.
+    ///
+    /// The configured level is restored even if `f` panics or the returned future is dropped
+    /// (in that case in background, on a task spawned on the current runtime). The output of
+    /// `f` is returned along with the result of the restore.
+    ///
+    /// ```rust,no_run
+    /// # use std::collections::BTreeSet;
+    /// # use nostr_sqlite::{SQLiteDatabase, Synchronous};
+    /// # use nostr_sqlite::database::NostrDatabase;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), nostr_sqlite::Error> {
+    /// # let db = SQLiteDatabase::open("nostr.db").await?;
+    /// # let events = BTreeSet::new();
+    /// let (imported, restored) = db
+    ///     .with_synchronous(Synchronous::Off, || db.bulk_import(events))
+    ///     .await?;
+    /// imported?;
+    /// restored?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn with_synchronous<F, Fut, T>(
+        &self,
+        level: Synchronous,
+        f: F,
+    ) -> Result<(T, Result<(), Error>), Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        self.check_writable()?;
+
+        // Armed before changing the level, so it's restored also if cancelled while setting it
+        let guard = SynchronousGuard {
+            pool: Some(self.pool.clone()),
+            level: self.opts.synchronous,
+        };
+
+        set_synchronous(&self.pool, level).await?;
+        let output: T = f().await;
+        let restored = guard.restore().await;
+
+        Ok((output, restored))
+    }
+
+    /// Set a handler called with the [`MetricEvent`] of every [`NostrDatabase`] operation
+    ///
+    /// The handler is shared by all the clones of this database and replaces the previous one.
+    /// It's called on the task that executed the operation, so it should return quickly.
+    pub fn set_metrics_handler<F>(&self, handler: F)
+    where
+        F: Fn(MetricEvent) + Send + Sync + 'static,
+    {
+        self.metrics.set_handler(Some(Arc::new(handler)));
+    }
+
+    /// Remove the metrics handler
+    pub fn remove_metrics_handler(&self) {
+        self.metrics.set_handler(None);
+    }
+
+    /// Run `f` on a read connection of the pool (i.e. for ad-hoc `SELECT`s)
+    ///
+    /// **Warning**: this is a read-only escape hatch. The read connections are opened in
+    /// read-only mode, but in-memory or non-WAL databases have none, so the writer is used
+    /// instead. Any mutation made through this connection bypasses the in-memory indexes,
+    /// which will then return wrong results for [`NostrDatabase::query`],
+    /// [`NostrDatabase::count`] and the other lookups until the database is reopened.
+    pub async fn with_read_connection<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        Ok(self.pool.interact_read(move |conn| f(conn)).await??)
+    }
+
+    /// Check that the database is reachable
+    ///
+    /// Reads the schema version from the database header, on the writer connection, without
+    /// touching the indexes. Fails if the connection is unusable or the file unreadable
+    /// (note that, on unix, a file deleted after opening is still readable).
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.pool
+            .interact(|conn| {
+                let _: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+                Ok(())
+            })
+            .await?
+    }
+
+    /// Full-text search on the content of the events, best matches first
+    ///
+    /// `query` uses the [FTS5 query syntax](https://www.sqlite.org/fts5.html#full_text_query_syntax).
+    ///
+    /// Requires the `fts` feature, otherwise [`Error::Unsupported`] is returned.
+    pub async fn search<S>(&self, query: S, limit: usize) -> Result<Vec<Event>, Error>
+    where
+        S: Into<String>,
+    {
+        #[cfg(feature = "fts")]
+        {
+            let query: String = query.into();
+            self.pool
+                .interact_read(move |conn| fts::search(conn, &query, limit))
+                .await?
+        }
+
+        #[cfg(not(feature = "fts"))]
+        {
+            let _ = (query, limit);
+            Err(Error::Unsupported(String::from(
+                "full-text search (enable the `fts` feature)",
+            )))
+        }
+    }
+
+    /// Copy the database to a new file, using the SQLite online backup API
+    ///
+    /// The backup is a standalone database that can be opened with [`SQLiteDatabase::open`].
+    /// The writer connection isn't used, unless the database is in-memory:
+    ///
+    /// * with [`JournalMode::Wal`] (and at least one read connection), the copy is made by a read
+    ///   connection in a single step, so it's a consistent snapshot and the writes proceed
+    ///   meanwhile (they aren't included in the copy);
+    /// * otherwise, it's made by a separate connection in steps of 1024 pages, pausing between
+    ///   them so that the writes can proceed. SQLite restarts the copy after every write, so it
+    ///   may not complete while the database is written continuously;
+    /// * in-memory databases have a single connection: the other operations wait for the copy.
+    pub async fn backup_to<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path: PathBuf = path.as_ref().to_path_buf();
+        if self.pool.has_readers() {
+            return self
+                .pool
+                .interact_read(move |conn| backup(conn, &path, -1))
+                .await?;
+        }
+
+        match self.path.clone() {
+            Some(source) => {
+                tokio::task::spawn_blocking(move || {
+                    let conn: Connection = open_reader(&source)?;
+                    backup(&conn, &path, BACKUP_PAGES_PER_STEP)
+                })
+                .await?
+            }
+            None => {
+                self.pool
+                    .interact(move |conn| backup(conn, &path, -1))
+                    .await?
+            }
+        }
+    }
+
+    /// Write a compacted copy of the database to a new file, with `VACUUM INTO`
+    ///
+    /// Unlike [`SQLiteDatabase::backup_to`], the copy is defragmented and has no unused pages, so
+    /// it's the smallest possible file. The target `path` must not already exist (or be an empty
+    /// file), otherwise an error is returned. Runs on a read connection, so writes aren't blocked.
+    pub async fn vacuum_into<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path: String = path
+            .as_ref()
+            .to_str()
+            .ok_or_else(|| {
+                Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "path is not valid UTF-8",
+                ))
+            })?
+            .to_string();
+        self.pool
+            .interact_read(move |conn| conn.execute("VACUUM INTO ?;", [path]))
+            .await??;
+        Ok(())
+    }
+
+    /// Replace the content of the database with the one of a backup file, rebuilding the indexes
+    ///
+    /// The backup is copied in a single write transaction on the destination, committed only
+    /// when the copy is completed: if it fails, the current data is left intact.
+    /// Backups of an older schema version are upgraded, while the ones created by a newer
+    /// version are rejected with [`Error::IncompatibleSchema`] before touching the current data.
+    pub async fn restore_from<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        self.check_writable()?;
+
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let opts = self.opts.clone();
+        self.pool
+            .interact(move |conn| {
+                let mut src = Connection::open_with_flags(
+                    path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+
+                // Reject backups that can't be used by this executable
+                let version: usize = migration::curr_db_version(&mut src)?;
+                if version > migration::DB_VERSION {
+                    return Err(Error::IncompatibleSchema {
+                        found: version as u32,
+                        supported: migration::DB_VERSION as u32,
+                    });
+                }
+
+                {
+                    let backup = Backup::new(&src, conn)?;
+                    backup.run_to_completion(BACKUP_PAGES_PER_STEP, Duration::ZERO, None)?;
+                }
+
+                // Re-apply options and upgrade schema, if needed
+                opts.apply(conn)?;
+                migration::run(conn, opts.strict_decode)?;
+                opts.apply_after_migrations(conn)?;
+                #[cfg(feature = "fts")]
+                fts::init(conn, opts.strict_decode)?;
+
+                Ok(())
+            })
+            .await??;
+        self.touch();
+
+        self.rebuild_indexes().await
+    }
+
+    /// Export all the stored events as JSON Lines (one JSON event per line), returning the number
+    /// of exported events
+    ///
+    /// The `events` table is read in batches, so only a batch at time is kept in memory and the
+    /// connection isn't held between the batches: events saved or deleted during the export may
+    /// or may not be included. Every line, including the last one, is terminated by `\n`.
+    pub async fn export_jsonl<W>(&self, mut writer: W) -> Result<usize, Error>
+    where
+        W: Write,
+    {
+        let mut last_rowid: i64 = 0;
+        let mut total: usize = 0;
+
+        loop {
+            let batch: Vec<(i64, String)> = self
+                .pool
+                .interact_read(move |conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT rowid, event, compression, format FROM events WHERE rowid > ? ORDER BY rowid ASC LIMIT ?;",
+                    )?;
+                    let mut rows = stmt.query((last_rowid, BATCH_SIZE))?;
+                    let mut batch: Vec<(i64, String)> = Vec::with_capacity(BATCH_SIZE);
+                    while let Some(row) = rows.next()? {
+                        let rowid: i64 = row.get(0)?;
+                        let event: Event = decode_event(row.get(1)?, row.get(2)?, row.get(3)?)?;
+                        batch.push((rowid, event.as_json()));
+                    }
+                    Ok::<Vec<(i64, String)>, Error>(batch)
+                })
+                .await??;
+
+            let len: usize = batch.len();
+            for (rowid, json) in batch.into_iter() {
+                writer.write_all(json.as_bytes())?;
+                writer.write_all(b"\n")?;
+                last_rowid = rowid;
+            }
+
+            total += len;
+            if len < BATCH_SIZE {
+                break;
+            }
+        }
+
+        writer.flush()?;
+        Ok(total)
+    }
+
+    /// Import events from JSON Lines (one JSON event per line), as written by
+    /// [`SQLiteDatabase::export_jsonl`]
+    ///
+    /// Every event is verified (ID and signature) and imported with [`NostrDatabase::bulk_import`],
+    /// in batches of 1000 events. Empty lines are ignored.
+    ///
+    /// If `strict` is `true`, the import is aborted on the first malformed line or invalid event,
+    /// otherwise these are skipped and counted in [`JsonlImport::skipped`].
+    /// The batches imported before the abort are kept.
+    pub async fn import_jsonl<R>(&self, reader: R, strict: bool) -> Result<JsonlImport, Error>
+    where
+        R: BufRead,
+    {
+        self.check_writable()?;
+
+        let mut report = JsonlImport::default();
+        let mut batch: BTreeSet<Event> = BTreeSet::new();
+
+        for (index, line) in reader.lines().enumerate() {
+            let line: String = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event: Result<Event, nostr::event::Error> =
+                Event::from_json(&line).and_then(|event| event.verify().map(|_| event));
+            match event {
+                Ok(event) => {
+                    batch.insert(event);
+                    report.imported += 1;
+                }
+                Err(e) => {
+                    if strict {
+                        return Err(Error::Event(e));
+                    }
+                    tracing::warn!("Skipping line {}: {e}", index + 1);
+                    report.skipped += 1;
+                }
+            }
+
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.bulk_import(std::mem::take(&mut batch)).await?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.bulk_import(batch).await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Delete the events with the given IDs, returning the number of deleted events
+    ///
+    /// Unlike [`NostrDatabase::delete`], the IDs aren't resolved by the indexes. All of them are
+    /// recorded as deleted (see [`SQLiteDatabase::deleted_ids`]), also if not stored, so that
+    /// they're rejected if received later.
+    pub async fn delete_by_ids(&self, ids: &[EventId]) -> Result<usize, Error> {
+        self.check_writable()?;
+
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let to_delete: Vec<EventId> = ids.to_vec();
+        let deleted: usize = self
+            .pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                insert_tombstones(&tx, &to_delete)?;
+                let deleted: usize = delete_events(&tx, &to_delete)?;
+                tx.commit()?;
+                Ok::<usize, Error>(deleted)
+            })
+            .await??;
+
+        self.indexes
+            .mark_as_deleted(ids.iter().copied().collect())
+            .await;
+        self.touch();
+
+        Ok(deleted)
+    }
+
+    /// Delete the events expired at `now` (NIP-40), returning the number of deleted events
+    ///
+    /// An event with expiration equal to `now` is considered expired, while the events without
+    /// the `expiration` tag are never deleted.
+    pub async fn delete_expired(&self, now: Timestamp) -> Result<usize, Error> {
+        self.check_writable()?;
+
+        let ids: Vec<EventId> = self
+            .pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                let ids: Vec<EventId> = select_event_ids(
+                    &tx,
+                    "SELECT event_id FROM events WHERE expiration IS NOT NULL AND expiration <= ?;",
+                    [now.as_u64()],
+                )?;
+                delete_events(&tx, &ids)?;
+                tx.commit()?;
+                Ok::<Vec<EventId>, Error>(ids)
+            })
+            .await??;
+
+        self.discard_from_indexes(&ids).await;
+
+        Ok(ids.len())
+    }
+
+    /// Delete the events created before `cutoff`, returning the number of deleted events
+    ///
+    /// Replaceable and parameterized replaceable events are deleted as well, and so the relays
+    /// that have seen the deleted events.
+    pub async fn delete_older_than(&self, cutoff: Timestamp) -> Result<usize, Error> {
+        self.check_writable()?;
+
+        let ids: Vec<EventId> = self
+            .pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                let ids: Vec<EventId> = select_event_ids(
+                    &tx,
+                    "SELECT event_id FROM events WHERE created_at < ?;",
+                    [cutoff.as_u64()],
+                )?;
+                delete_events(&tx, &ids)?;
+                tx.commit()?;
+                Ok::<Vec<EventId>, Error>(ids)
+            })
+            .await??;
+
+        self.discard_from_indexes(&ids).await;
+
+        Ok(ids.len())
+    }
+
+    /// Delete the older versions of the replaceable (and parameterized replaceable) events,
+    /// keeping only the newest one of each coordinate, and return how many were removed
+    ///
+    /// They're normally deleted when the newer version is saved: this repairs the database
+    /// after an import done outside of this instance. The coordinates are fixed too.
+    pub async fn compact_replaceable(&self) -> Result<usize, Error> {
+        self.check_writable()?;
+
+        let ids: Vec<EventId> = self
+            .pool
+            .interact(|conn| {
+                let tx = conn.transaction()?;
+                let ids: Vec<EventId> = superseded_events(&tx)?;
+                delete_events(&tx, &ids)?;
+                tx.commit()?;
+                Ok::<Vec<EventId>, Error>(ids)
+            })
+            .await??;
+
+        self.discard_from_indexes(&ids).await;
+
+        Ok(ids.len())
+    }
+
+    /// Save multiple events, returning, for each of them, if it was stored
+    ///
+    /// Equivalent to calling [`NostrDatabase::save_event`] for each event (in order), but all
+    /// the events are encoded while holding the FlatBuffers builder once and saved in a single
+    /// transaction. An event replaced by a following one of the same batch is reported as
+    /// stored (as `save_event` would do) but isn't written.
+    pub async fn save_events(&self, events: &[Event]) -> Result<Vec<bool>, Error> {
+        self.check_writable()?;
+        let (stored, _) = self.store_events(events.iter().cloned()).await?;
+        Ok(stored)
+    }
+
+    /// Like [`NostrDatabase::bulk_import`], but returning how many events were stored,
+    /// discarded or ignored
+    pub async fn bulk_import_counted(
+        &self,
+        events: BTreeSet<Event>,
+    ) -> Result<BulkImportResult, Error> {
+        self.check_writable()?;
+        let (_, result) = self.store_events(events).await?;
+        self.import_checkpoint().await?;
+        Ok(result)
+    }
+
+    /// Like [`NostrDatabase::save_event`], but returning if the event was stored, replaced an
+    /// older event, was rejected or was already stored
+    ///
+    /// [`NostrDatabase::save_event`] returns `true` for [`SaveOutcome::Stored`] and
+    /// [`SaveOutcome::Replaced`].
+    pub async fn save_event_outcome(&self, event: &Event) -> Result<SaveOutcome, Error> {
+        self.save_event_impl(event, None, ConflictMode::Ignore)
+            .await
+    }
+
+    /// Like [`SQLiteDatabase::save_event_outcome`], choosing what to do if the event is already
+    /// stored (same ID)
+    pub async fn save_event_with(
+        &self,
+        event: &Event,
+        on_conflict: ConflictMode,
+    ) -> Result<SaveOutcome, Error> {
+        self.save_event_impl(event, None, on_conflict).await
+    }
+
+    /// Save [`Event`] received from a relay, marking it as seen by the relay
+    ///
+    /// Like [`NostrDatabase::save_event`] followed by [`NostrDatabase::event_id_seen`], but in
+    /// a single transaction. The relay is recorded even if the event was already stored or is
+    /// rejected.
+    pub async fn save_event_from(&self, event: &Event, relay_url: &Url) -> Result<bool, Error> {
+        let outcome: SaveOutcome = self
+            .save_event_impl(event, Some(relay_url), ConflictMode::Ignore)
+            .await?;
+        Ok(matches!(
+            outcome,
+            SaveOutcome::Stored | SaveOutcome::Replaced
+        ))
+    }
+
+    async fn save_event_impl(
+        &self,
+        event: &Event,
+        relay_url: Option<&Url>,
+        on_conflict: ConflictMode,
+    ) -> Result<SaveOutcome, Error> {
+        let relay_url: Option<Url> = relay_url.cloned();
+        self.metrics
+            .measure(
+                metrics::Operation::SaveEvent,
+                async move {
+                    self.check_writable()?;
+
+                    if event.is_expired() || (event.is_ephemeral() && !self.opts.store_ephemeral) {
+                        self.save_seen(event.id(), relay_url).await?;
+                        return Ok(SaveOutcome::Rejected);
+                    }
+
+                    // Index event
+                    let EventIndexResult {
+                        to_store,
+                        to_discard,
+                    } = self.index_event(event).await;
+
+                    // Deleted events are discarded too
+                    let deleted: bool = to_discard.contains(&event.id());
+                    let replaced: bool = to_store
+                        && (event.is_replaceable() || event.is_parameterized_replaceable())
+                        && !to_discard.is_empty();
+
+                    if !to_discard.is_empty() {
+                        let to_discard: Vec<EventId> = to_discard.into_iter().collect();
+                        let tombstones: bool = event.kind() == Kind::EventDeletion;
+                        self.pool
+                            .interact(move |conn| {
+                                let tx = conn.transaction()?;
+                                if tombstones {
+                                    insert_tombstones(&tx, &to_discard)?;
+                                }
+                                delete_events(&tx, &to_discard)?;
+                                tx.commit()?;
+                                Ok::<(), Error>(())
+                            })
+                            .await??;
+                        self.touch();
+                    }
+
+                    if to_store {
+                        // Encode (the builder is released before the insert)
+                        let format: StorageFormat = self.opts.storage_format;
+                        let value: Vec<u8> = {
+                            let mut fbb = self.fbb.write().await;
+                            encode_event(&mut fbb, event, self.opts.flatbuffer_capacity, format)
+                        };
+
+                        // Save event (and the relay that has seen it)
+                        let cloned: Event = event.clone();
+                        let compression: Option<i32> = self.opts.compression;
+                        let seen: bool = relay_url.is_some();
+                        let inserted: bool = self
+                            .pool
+                            .interact(move |conn| {
+                                let tx = conn.transaction()?;
+                                let inserted: bool =
+                                    insert_event(&tx, &cloned, value, format, compression)?;
+                                if let Some(relay_url) = relay_url {
+                                    insert_seen(&tx, &cloned.id(), &relay_url)?;
+                                }
+                                tx.commit()?;
+                                Ok::<bool, Error>(inserted)
+                            })
+                            .await??;
+
+                        if seen {
+                            self.count_seen(1).await?;
+                        }
+
+                        if !inserted {
+                            return self.resolve_conflict(event, on_conflict).await;
+                        }
+
+                        self.notify([event]);
+                        self.touch();
+                        self.count_writes(1).await?;
+
+                        if replaced {
+                            Ok(SaveOutcome::Replaced)
+                        } else {
+                            Ok(SaveOutcome::Stored)
+                        }
+                    } else if deleted {
+                        self.save_seen(event.id(), relay_url).await?;
+                        Ok(SaveOutcome::Rejected)
+                    } else {
+                        // Already indexed or older than the stored replaceable event
+                        let event_id: EventId = event.id();
+                        let exists: bool = match relay_url {
+                            Some(relay_url) => {
+                                let exists: bool = self
+                                    .pool
+                                    .interact(move |conn| {
+                                        insert_seen(conn, &event_id, &relay_url)?;
+                                        event_exists(conn, &event_id)
+                                    })
+                                    .await??;
+                                self.count_seen(1).await?;
+                                exists
+                            }
+                            None => {
+                                self.pool
+                                    .interact_read(move |conn| event_exists(conn, &event_id))
+                                    .await??
+                            }
+                        };
+                        if exists {
+                            self.resolve_conflict(event, on_conflict).await
+                        } else {
+                            Ok(SaveOutcome::Rejected)
+                        }
+                    }
+                },
+                |outcome| matches!(outcome, SaveOutcome::Stored | SaveOutcome::Replaced) as usize,
+            )
+            .await
+    }
+
+    /// Handle an event already stored, according to `on_conflict`
+    async fn resolve_conflict(
+        &self,
+        event: &Event,
+        on_conflict: ConflictMode,
+    ) -> Result<SaveOutcome, Error> {
+        match on_conflict {
+            ConflictMode::Ignore => Ok(SaveOutcome::AlreadyHave),
+            ConflictMode::Replace => {
+                let format: StorageFormat = self.opts.storage_format;
+                let value: Vec<u8> = {
+                    let mut fbb = self.fbb.write().await;
+                    encode_event(&mut fbb, event, self.opts.flatbuffer_capacity, format)
+                };
+                let event_id: EventId = event.id();
+                let compression: Option<i32> = self.opts.compression;
+                self.pool
+                    .interact(move |conn| {
+                        let (value, compression) = compression::compress(value, compression)?;
+                        let mut stmt = conn.prepare_cached(
+                            "UPDATE events SET event = ?, compression = ?, format = ? WHERE event_id = ?;",
+                        )?;
+                        stmt.execute((value, compression, format.flag(), event_id.to_hex()))?;
+                        Ok::<(), Error>(())
+                    })
+                    .await??;
+                Ok(SaveOutcome::AlreadyHave)
+            }
+            ConflictMode::Error => Err(Error::Conflict(event.id())),
+        }
+    }
+
+    /// Mark the event as seen by the relay, if any
+    async fn save_seen(&self, event_id: EventId, relay_url: Option<Url>) -> Result<(), Error> {
+        if let Some(relay_url) = relay_url {
+            self.pool
+                .interact(move |conn| insert_seen(conn, &event_id, &relay_url))
+                .await??;
+            self.count_seen(1).await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`SQLiteDatabase::bulk_import_counted`], but verifying the ID and the signature of
+    /// each event first
+    ///
+    /// The invalid events are logged and skipped (reported as `rejected`), so that an untrusted
+    /// dump can't store forged events.
+    pub async fn bulk_import_verified(
+        &self,
+        events: BTreeSet<Event>,
+    ) -> Result<BulkImportResult, Error> {
+        self.check_writable()?;
+
+        let mut rejected: usize = 0;
+        let events: BTreeSet<Event> = events
+            .into_iter()
+            .filter(|event| match event.verify() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("Rejecting event {}: {e}", event.id());
+                    rejected += 1;
+                    false
+                }
+            })
+            .collect();
+
+        let (_, result) = self.store_events(events).await?;
+        self.import_checkpoint().await?;
+        Ok(BulkImportResult { rejected, ..result })
+    }
+
+    /// Index and save events in a single transaction, returning if each of them was stored
+    async fn store_events<I>(&self, events: I) -> Result<(Vec<bool>, BulkImportResult), Error>
+    where
+        I: IntoIterator<Item = Event>,
+    {
+        // Index events, collecting the ones replaced or deleted
+        let mut stored: Vec<bool> = Vec::new();
+        let mut to_store: Vec<Event> = Vec::new();
+        let mut to_discard: HashSet<EventId> = HashSet::new();
+        let mut tombstones: Vec<EventId> = Vec::new();
+        for event in events.into_iter() {
+            let EventIndexResult {
+                to_store: store,
+                to_discard: discard,
+            } = self.index_event(&event).await;
+            if event.kind() == Kind::EventDeletion {
+                tombstones.extend(discard.iter().copied());
+            }
+            if store {
+                to_store.push(event);
+            }
+            stored.push(store);
+            to_discard.extend(discard);
+        }
+
+        // Skip events replaced by others of the same batch
+        to_store.retain(|e| !to_discard.contains(&e.id()));
+        let to_discard: Vec<EventId> = to_discard.into_iter().collect();
+
+        // Acquire FlatBuffers Builder
+        let mut fbb = self.fbb.write().await;
+
+        // Encode
+        let capacity: usize = self.opts.flatbuffer_capacity;
+        let format: StorageFormat = self.opts.storage_format;
+        let events: Vec<(Event, Vec<u8>)> = to_store
+            .into_iter()
+            .map(move |e| {
+                let value: Vec<u8> = encode_event(&mut fbb, &e, capacity, format);
+                (e, value)
+            })
+            .collect();
+
+        // Bulk save
+        let compression: Option<i32> = self.opts.compression;
+        let (inserted, discarded) = self
+            .pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+
+                insert_tombstones(&tx, &tombstones)?;
+                let discarded: usize = delete_events(&tx, &to_discard)?;
+
+                let mut inserted: Vec<Event> = Vec::new();
+                for (event, value) in events.into_iter() {
+                    if insert_event(&tx, &event, value, format, compression)? {
+                        inserted.push(event);
+                    }
+                }
+
+                tx.commit()?;
+                Ok::<(Vec<Event>, usize), Error>((inserted, discarded))
+            })
+            .await??;
+
+        self.notify(inserted.iter());
+        let inserted: usize = inserted.len();
+        if inserted > 0 || discarded > 0 {
+            self.touch();
+        }
+        self.count_writes(inserted).await?;
+
+        let result = BulkImportResult {
+            stored: inserted,
+            discarded,
+            ignored: stored.len() - inserted,
+            rejected: 0,
+        };
+        Ok((stored, result))
+    }
+
+    /// Execute the operations recorded by `f` atomically
+    ///
+    /// The recorded operations are executed in order, in a single SQLite transaction: either all
+    /// of them are committed or none. The indexes are updated while the operations are prepared,
+    /// before the commit: if the transaction fails, they are rebuilt from the database, so that
+    /// they match its committed state again. Concurrent queries executed in the meantime may
+    /// count the events being saved (not yet returned, since they aren't stored yet) or miss
+    /// the ones being deleted.
+    ///
+    /// ```rust,no_run
+    /// # use nostr_sqlite::nostr::{Event, Url};
+    /// # use nostr_sqlite::SQLiteDatabase;
+    /// # async fn example(db: SQLiteDatabase, event: Event, relay_url: Url) -> Result<(), nostr_sqlite::Error> {
+    /// let id = event.id();
+    /// db.transaction(|tx| {
+    ///     tx.save_event(event);
+    ///     tx.event_id_seen(id, relay_url);
+    /// })
+    /// .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn transaction<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Transaction),
+    {
+        self.check_writable()?;
+
+        let mut transaction = Transaction::default();
+        f(&mut transaction);
+        if transaction.is_empty() {
+            return Ok(());
+        }
+
+        if transaction.has_deletions() {
+            self.ensure_indexes().await?;
+        }
+
+        // Index events and resolve the deletions, in order
+        let mut fbb = self.fbb.write().await;
+        let mut steps: Vec<TransactionStep> = Vec::new();
+        for operation in transaction.into_operations().into_iter() {
+            match operation {
+                TransactionOperation::SaveEvent(event) => {
+                    let EventIndexResult {
+                        to_store,
+                        to_discard,
+                    } = self.index_event(&event).await;
+                    if !to_discard.is_empty() {
+                        let ids: Vec<EventId> = to_discard.into_iter().collect();
+                        if event.kind() == Kind::EventDeletion {
+                            steps.push(TransactionStep::Tombstone(ids.clone()));
+                        }
+                        steps.push(TransactionStep::Delete(ids));
+                    }
+                    if to_store {
+                        let value: Vec<u8> = encode_event(
+                            &mut fbb,
+                            &event,
+                            self.opts.flatbuffer_capacity,
+                            self.opts.storage_format,
+                        );
+                        steps.push(TransactionStep::Insert(event, value));
+                    }
+                }
+                TransactionOperation::EventIdSeen(event_id, relay_url) => {
+                    steps.push(TransactionStep::Seen(event_id, relay_url));
+                }
+                TransactionOperation::Delete(filter) => match self.indexes.delete(filter).await {
+                    Some(ids) => steps.push(TransactionStep::Delete(ids.into_iter().collect())),
+                    None => steps.push(TransactionStep::DeleteAll),
+                },
+            }
+        }
+        drop(fbb);
+
+        let seen: usize = steps
+            .iter()
+            .filter(|step| matches!(step, TransactionStep::Seen(..)))
+            .count();
+        let compression: Option<i32> = self.opts.compression;
+        let format: StorageFormat = self.opts.storage_format;
+        let res = self
+            .pool
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                let mut inserted: Vec<Event> = Vec::new();
+                for step in steps.into_iter() {
+                    match step {
+                        TransactionStep::Insert(event, value) => {
+                            if insert_event(&tx, &event, value, format, compression)? {
+                                inserted.push(event);
+                            }
+                        }
+                        TransactionStep::Seen(event_id, relay_url) => {
+                            insert_seen(&tx, &event_id, &relay_url)?;
+                        }
+                        TransactionStep::Tombstone(ids) => {
+                            insert_tombstones(&tx, &ids)?;
+                        }
+                        TransactionStep::Delete(ids) => {
+                            delete_events(&tx, &ids)?;
+                        }
+                        TransactionStep::DeleteAll => delete_all_events(&tx)?,
+                    }
+                }
+                tx.commit()?;
+                Ok::<Vec<Event>, Error>(inserted)
+            })
+            .await
+            .and_then(|res| res);
+
+        match res {
+            Ok(inserted) => {
+                self.notify(inserted.iter());
+                self.touch();
+                self.count_writes(inserted.len()).await?;
+                self.count_seen(seen).await
+            }
+            Err(e) => {
+                tracing::error!("Transaction failed, rebuilding indexes: {e}");
+                self.rebuild_indexes().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Index [`Event`], storing the ephemeral ones without indexing them if
+    /// [`SQLiteOptions::store_ephemeral`] is enabled
+    async fn index_event(&self, event: &Event) -> EventIndexResult {
+        if self.opts.store_ephemeral && event.is_ephemeral() && !event.is_expired() {
+            EventIndexResult {
+                to_store: true,
+                to_discard: HashSet::new(),
+            }
+        } else {
+            self.indexes.index_event(event).await
+        }
+    }
+
+    /// Checkpoint the WAL after a bulk import, if larger than the configured threshold
+    async fn import_checkpoint(&self) -> Result<(), Error> {
+        if let Some(threshold) = self.opts.import_checkpoint_threshold {
+            self.pool
+                .interact(move |conn| checkpoint(conn, Some(threshold)))
+                .await??;
+        }
+        Ok(())
+    }
+
+    /// Remove events, already deleted from the database, from the indexes
+    async fn discard_from_indexes(&self, ids: &[EventId]) {
+        // An empty filter would clear the indexes
+        if !ids.is_empty() {
+            self.indexes
+                .delete(Filter::new().ids(ids.iter().copied()))
+                .await;
+            self.touch();
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.opts.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn build_indexes(&self) -> Result<(), Error> {
+        self.build_indexes_with_progress(None).await
+    }
+
+    /// Build the indexes, if not done yet (see [`SQLiteOptions::lazy_indexes`])
+    async fn ensure_indexes(&self) -> Result<(), Error> {
+        self.indexes_built
+            .get_or_try_init(|| self.build_indexes())
+            .await?;
+        Ok(())
+    }
+
+    fn set_indexes_built(&self) {
+        let _ = self.indexes_built.set(());
+    }
+
+    /// Clear the indexes and build them again from the stored events
+    async fn rebuild_indexes(&self) -> Result<(), Error> {
+        self.indexes.clear().await;
+        self.build_indexes().await?;
+        self.set_indexes_built();
+        Ok(())
+    }
+
+    /// Load the events in batches of [`LOAD_BATCH_SIZE`] rows, indexing each batch once decoded
+    #[tracing::instrument(skip_all)]
+    async fn build_indexes_with_progress(
+        &self,
+        progress: Option<Progress<'_>>,
+    ) -> Result<(), Error> {
+        let total: usize = self
+            .pool
+            .interact(|conn| {
+                let count: i64 =
+                    conn.query_row("SELECT COUNT(*) FROM events;", [], |row| row.get(0))?;
+                Ok::<_, Error>(count as usize)
+            })
+            .await??;
+
+        // Restore the deletions first, so that the deleted events are never indexed
+        let deleted: HashSet<EventId> = self
+            .pool
+            .interact(|conn| select_event_ids(conn, "SELECT event_id FROM deleted_events;", ()))
+            .await??
+            .into_iter()
+            .collect();
+        self.indexes.mark_as_deleted(deleted).await;
+
+        let strict: bool = self.opts.strict_decode;
+        let mut last_rowid: i64 = 0;
+        let mut loaded: usize = 0;
+        let mut to_discard: HashSet<EventId> = HashSet::new();
+        let mut corrupt: Vec<(i64, String)> = Vec::new();
+        loop {
+            let (events, json_events, batch_corrupt, rows, last) = self
+                .pool
+                .interact(move |conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT rowid, event_id, event, compression, format FROM events WHERE rowid > ? ORDER BY rowid ASC LIMIT ?;",
+                    )?;
+                    let mut rows = stmt.query((last_rowid, LOAD_BATCH_SIZE))?;
+                    let mut events = BTreeSet::new();
+                    let mut json_events = BTreeSet::new();
+                    let mut corrupt: Vec<(i64, String)> = Vec::new();
+                    let mut num: usize = 0;
+                    let mut last: i64 = last_rowid;
+                    while let Some(row) = rows.next()? {
+                        num += 1;
+                        last = row.get(0)?;
+                        let format: u8 = row.get(4)?;
+                        let decoded = if format == format::JSON {
+                            decode_event::<Event>(row.get(2)?, row.get(3)?, format)
+                                .map(|event| json_events.insert(event))
+                        } else {
+                            decode_event::<TempEvent>(row.get(2)?, row.get(3)?, format)
+                                .map(|raw| events.insert(raw))
+                        };
+                        match decoded {
+                            Ok(_) => (),
+                            // Not corrupt, but can't be decoded by this build (i.e. feature disabled)
+                            Err(e @ Error::Unsupported(_)) => return Err(e),
+                            Err(e) if strict => return Err(e),
+                            Err(e) => {
+                                let event_id: String = row.get(1)?;
+                                tracing::error!("Impossible to decode event {event_id}: {e}");
+                                corrupt.push((last, event_id));
+                            }
+                        }
+                    }
+                    Ok::<_, Error>((events, json_events, corrupt, num, last))
+                })
+                .await??;
+
+            to_discard.extend(self.indexes.bulk_index(events).await);
+            to_discard.extend(self.indexes.bulk_index(json_events).await);
+            corrupt.extend(batch_corrupt);
+            loaded += rows;
+            last_rowid = last;
+
+            // Rows may be added while loading (i.e. by another process)
+            let total: usize = total.max(loaded);
+            if let Some(progress) = progress {
+                progress(loaded, total);
+            }
+
+            if rows < LOAD_BATCH_SIZE {
+                break;
+            }
+        }
+
+        if !corrupt.is_empty() {
+            tracing::warn!("Skipped {} events that can't be decoded", corrupt.len());
+            if self.opts.delete_corrupt && !self.opts.read_only {
+                self.pool
+                    .interact(move |conn| {
+                        let tx = conn.transaction()?;
+                        delete_corrupt_events(&tx, &corrupt)?;
+                        tx.commit()?;
+                        Ok::<(), Error>(())
+                    })
+                    .await??;
+            }
+        }
+
+        // Discard events (can't be removed from the database if read-only)
+        if !to_discard.is_empty() && !self.opts.read_only {
+            let to_discard: Vec<EventId> = to_discard.into_iter().collect();
+            self.pool
+                .interact(move |conn| {
+                    let tx = conn.transaction()?;
+                    delete_events(&tx, &to_discard)?;
+                    tx.commit()?;
+                    Ok::<(), Error>(())
+                })
+                .await??;
+        }
+        Ok(())
+    }
+}
+
+/// Checkpoint and truncate the WAL (only if larger than `threshold` bytes, if set)
+fn checkpoint(conn: &Connection, threshold: Option<u64>) -> Result<(), Error> {
+    let mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        return Ok(());
+    }
+
+    if let Some(threshold) = threshold {
+        // A passive checkpoint returns the number of frames in the WAL, without blocking
+        let frames: i64 =
+            conn.query_row("PRAGMA wal_checkpoint(PASSIVE);", [], |row| row.get(1))?;
+        let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        if (frames.max(0) as u64) * page_size <= threshold {
+            return Ok(());
+        }
+    }
+
+    let busy: bool = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| row.get(0))?;
+    if busy {
+        tracing::warn!("WAL checkpoint not completed: database busy");
+    }
+    Ok(())
+}
+
+/// Passive checkpoint of the WAL
+fn flush(conn: &Connection) -> Result<(), Error> {
+    let mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    if mode.eq_ignore_ascii_case("wal") {
+        conn.query_row("PRAGMA wal_checkpoint(PASSIVE);", [], |_| Ok(()))?;
+    }
+    Ok(())
+}
+
+/// Run [`flush`] every `interval`, until the pool is dropped
+///
+/// The pool is only held while flushing, so it doesn't keep the database open.
+fn spawn_flush_task(pool: WeakPool, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let pool: Pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => break,
+            };
+            if let Err(e) = pool.interact(|conn| flush(conn)).await.and_then(|res| res) {
+                tracing::warn!("Periodic WAL checkpoint failed: {e}");
+            }
+        }
+    });
+}
+
+/// Set the synchronous level of the writer connection
+async fn set_synchronous(pool: &Pool, level: Synchronous) -> Result<(), Error> {
+    pool.interact(move |conn| conn.pragma_update(None, "synchronous", level.as_str()))
+        .await??;
+    Ok(())
+}
+
+/// Run an integrity check pragma, collecting the returned messages
+fn check_pragma(conn: &Connection, pragma: &str) -> Result<Vec<String>, Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA {pragma};"))?;
+    let mut rows = stmt.query([])?;
+    let mut messages: Vec<String> = Vec::new();
+    while let Some(row) = rows.next()? {
+        messages.push(row.get(0)?);
+    }
+    Ok(messages)
+}
+
+/// Build an SQL condition on the indexed columns, matched by the rows that may match `filters`,
+/// with its bound parameters
+///
+/// The indexes still do the precise matching: this only narrows the rows read from the `events`
+/// table. Returns `None` if at least one of the filters isn't restricted by the indexed columns.
+fn prefilter_condition(filters: &[Filter]) -> Option<(String, Vec<Value>)> {
+    let mut conditions: Vec<String> = Vec::with_capacity(filters.len());
+    let mut params: Vec<Value> = Vec::new();
+    for filter in filters.iter() {
+        conditions.push(filter_condition(filter, &mut params)?);
+    }
+
+    if conditions.is_empty() {
+        None
+    } else {
+        Some((conditions.join(" OR "), params))
+    }
+}
+
+/// Build an SQL condition from the fields of `filter` stored in columns (or in `event_tags`),
+/// appending its bound parameters to `params`
+///
+/// Returns `None` if the filter doesn't restrict any of these fields (it matches every row).
+fn filter_condition(filter: &Filter, params: &mut Vec<Value>) -> Option<String> {
+    let mut conditions: Vec<String> = Vec::new();
+
+    if let Some(ids) = &filter.ids {
+        if !ids.is_empty() {
+            let ids: Vec<Value> = ids.iter().map(|id| Value::Text(id.to_hex())).collect();
+            conditions.push(format!("event_id IN {}", in_list(ids, params)));
+        }
+    }
+
+    if let Some(kinds) = &filter.kinds {
+        if !kinds.is_empty() {
+            let kinds: Vec<Value> = kinds.iter().map(|k| Value::from(k.as_u32())).collect();
+            conditions.push(format!("kind IN {}", in_list(kinds, params)));
+        }
+    }
+
+    if let Some(authors) = &filter.authors {
+        if !authors.is_empty() {
+            let authors: Vec<Value> = authors.iter().map(|a| Value::Text(a.to_string())).collect();
+            conditions.push(format!("author IN {}", in_list(authors, params)));
+        }
+    }
+
+    if let Some(since) = filter.since {
+        conditions.push(String::from("created_at >= ?"));
+        params.push(Value::Integer(since.as_i64()));
+    }
+
+    if let Some(until) = filter.until {
+        conditions.push(String::from("created_at <= ?"));
+        params.push(Value::Integer(until.as_i64()));
+    }
+
+    for (tag, values) in filter.generic_tags.iter() {
+        params.push(Value::Text(tag.as_char().to_string()));
+        let values: Vec<Value> = values.iter().map(|v| Value::Text(v.to_string())).collect();
+        conditions.push(format!(
+            "event_id IN (SELECT event_id FROM event_tags WHERE tag_name = ? AND tag_value IN {})",
+            in_list(values, params)
+        ));
+    }
+
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(format!("({})", conditions.join(" AND ")))
+    }
+}
+
+/// Build an `IN` list binding `values`, appending them to `params`
+///
+/// Above [`MAX_IN_PARAMS`] values, they're bound as a single JSON array (read with
+/// `json_each`), to keep the statement below `SQLITE_MAX_VARIABLE_NUMBER`.
+fn in_list(values: Vec<Value>, params: &mut Vec<Value>) -> String {
+    if values.len() > MAX_IN_PARAMS {
+        let array: Vec<serde_json::Value> = values
+            .into_iter()
+            .map(|value| match value {
+                Value::Integer(i) => serde_json::Value::from(i),
+                Value::Text(s) => serde_json::Value::from(s),
+                _ => serde_json::Value::Null,
+            })
+            .collect();
+        params.push(Value::Text(serde_json::Value::Array(array).to_string()));
+        String::from("(SELECT value FROM json_each(?))")
+    } else {
+        let placeholders: String = vec!["?"; values.len()].join(", ");
+        params.extend(values);
+        format!("({placeholders})")
+    }
+}
+
+/// Build the query selecting the events matching `filters`, sorted like the indexes
+///
+/// Each filter's `limit` keeps its newest events. The ties on `created_at` are broken by
+/// event ID (ascending with [`Order::Desc`], descending with [`Order::Asc`]). The ephemeral
+/// events are excluded if `exclude_ephemeral` (they're never indexed). Returns the statement
+/// with its bound parameters.
+fn sorted_query(filters: &[Filter], order: Order, exclude_ephemeral: bool) -> (String, Vec<Value>) {
+    let ephemeral: &str = "NOT (kind BETWEEN 20000 AND 29999)";
+    let mut params: Vec<Value> = Vec::new();
+    let conditions: Vec<Option<String>> = filters
+        .iter()
+        .map(
+            |filter| match (filter_condition(filter, &mut params), exclude_ephemeral) {
+                (Some(condition), true) => Some(format!("{condition} AND {ephemeral}")),
+                (None, true) => Some(ephemeral.to_string()),
+                (condition, false) => condition,
+            },
+        )
+        .collect();
+
+    let condition: Option<String> = if filters.iter().any(|f| f.limit.is_some()) {
+        let selects: Vec<String> = filters
+            .iter()
+            .zip(conditions.iter())
+            .map(|(filter, condition)| {
+                let condition: String = match condition {
+                    Some(condition) => format!("WHERE {condition}"),
+                    None => String::new(),
+                };
+                match filter.limit {
+                    Some(limit) => format!(
+                        "SELECT event_id FROM (SELECT event_id FROM events {condition} ORDER BY created_at DESC, event_id ASC LIMIT {limit})"
+                    ),
+                    None => format!("SELECT event_id FROM events {condition}"),
+                }
+            })
+            .collect();
+        Some(format!("event_id IN ({})", selects.join(" UNION ")))
+    } else if conditions.iter().any(|c| c.is_none()) {
+        // At least one filter matches every row
+        params.clear();
+        None
+    } else {
+        let conditions: Vec<String> = conditions.into_iter().flatten().collect();
+        Some(conditions.join(" OR "))
+    };
+
+    let condition: String = match condition {
+        Some(condition) => format!("WHERE {condition}"),
+        None => String::new(),
+    };
+    let sort: &str = match order {
+        Order::Asc => "created_at ASC, event_id DESC",
+        Order::Desc => "created_at DESC, event_id ASC",
+    };
+    let sql: String =
+        format!("SELECT event, compression, format FROM events {condition} ORDER BY {sort};");
+    (sql, params)
+}
+
+/// Run `EXPLAIN QUERY PLAN` on `sql` (binding `params`), one step per line, indented by depth
+fn explain(conn: &Connection, sql: &str, params: &[Value]) -> Result<String, Error> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    let mut plan: String = String::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let parent: i64 = row.get(1)?;
+        let detail: String = row.get(3)?;
+        let depth: usize = depths.get(&parent).map(|d| d + 1).unwrap_or(0);
+        depths.insert(id, depth);
+        plan.push_str(&format!("{}{detail}\n", "  ".repeat(depth)));
+    }
+    Ok(plan)
+}
+
+/// Decode the events selected by `sql` (the `event`, `compression` and `format` columns),
+/// binding `params`
+///
+/// Undecodable events are skipped (and logged), unless `strict`. The statement is built from
+/// the filters, so it isn't kept in the statement cache.
+fn select_events(
+    conn: &Connection,
+    sql: &str,
+    params: Vec<Value>,
+    strict: bool,
+) -> Result<Vec<Event>, Error> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut events: Vec<Event> = Vec::new();
+    while let Some(row) = rows.next()? {
+        match decode_event(row.get(0)?, row.get(1)?, row.get(2)?) {
+            Ok(event) => events.push(event),
+            Err(e) if strict => return Err(e),
+            Err(e) => {
+                tracing::error!("Impossible to decode event: {e}")
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Check if `filter` can be fully evaluated with [`filter_condition`] (no search)
+fn is_sql_filter(filter: &Filter) -> bool {
+    filter.search.is_none()
+}
+
+/// Set the permissions of the newly created database file
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: Option<u32>) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_path: &Path, _mode: Option<u32>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Copy `src` into a new database at `path` with the online backup API, `pages_per_step` at
+/// a time (all of them if negative), pausing between the steps
+fn backup(src: &Connection, path: &Path, pages_per_step: c_int) -> Result<(), Error> {
+    let mut dst = Connection::open(path)?;
+    let backup = Backup::new(src, &mut dst)?;
+    loop {
+        match backup.step(pages_per_step)? {
+            StepResult::Done => return Ok(()),
+            // More, Busy or Locked (non-exhaustive)
+            _ => std::thread::sleep(BACKUP_STEP_PAUSE),
+        }
+    }
+}
+
+/// Open a read-only connection
+fn open_reader(path: &Path) -> Result<Connection, Error> {
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+/// Collect the [`EventId`] selected by a query returning the `event_id` column
+fn select_event_ids<P>(conn: &Connection, sql: &str, params: P) -> Result<Vec<EventId>, Error>
+where
+    P: Params,
+{
+    let mut stmt = conn.prepare_cached(sql)?;
+    let mut rows = stmt.query(params)?;
+    let mut ids: Vec<EventId> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        ids.push(EventId::from_hex(id)?);
+    }
+    Ok(ids)
+}
+
+/// Encode an event, replacing the builder if it had to grow over `capacity` to fit it
+///
+/// Otherwise a single large event would keep the memory of the shared builder allocated.
+fn encode_event(
+    fbb: &mut FlatBufferBuilder<'static>,
+    event: &Event,
+    capacity: usize,
+    format: StorageFormat,
+) -> Vec<u8> {
+    match format {
+        StorageFormat::FlatBuffer => {
+            let value: Vec<u8> = event.encode(fbb).to_vec();
+            if value.len() > capacity {
+                *fbb = FlatBufferBuilder::with_capacity(capacity);
+            }
+            value
+        }
+        StorageFormat::Json => event.as_json().into_bytes(),
+    }
+}
+
+/// Decode a stored event, decompressing it if needed
+pub(crate) fn decode_event<T>(value: Vec<u8>, compression: u8, format: u8) -> Result<T, Error>
+where
+    T: Decode,
+{
+    let buf: Vec<u8> = compression::decompress(value, compression)?;
+    T::decode_format(&buf, format)
+}
+
+/// Get events by [`EventId`], in the same order of `ids` (missing events are skipped)
+fn fetch_events(conn: &Connection, ids: &[EventId]) -> Result<Vec<Event>, Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let placeholders: String = vec!["?"; ids.len()].join(",");
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT event, compression, format FROM events WHERE event_id IN ({placeholders});"
+    ))?;
+    let mut rows = stmt.query(params_from_iter(ids.iter().map(|id| id.to_hex())))?;
+    let mut events: HashMap<EventId, Event> = HashMap::with_capacity(ids.len());
+    while let Some(row) = rows.next()? {
+        let event: Event = decode_event(row.get(0)?, row.get(1)?, row.get(2)?)?;
+        events.insert(event.id(), event);
+    }
+    Ok(ids.iter().filter_map(|id| events.remove(id)).collect())
+}
+
+/// Load the queried events, in the order of `ids`, skipping the ones not matching `prefilter`
+///
+/// Undecodable events are skipped (and logged), unless `strict`.
+fn load_events(
+    conn: &Connection,
+    ids: &[EventId],
+    prefilter: Option<&(String, Vec<Value>)>,
+    strict: bool,
+) -> Result<Vec<Event>, Error> {
+    let mut events: HashMap<EventId, Event> = HashMap::with_capacity(ids.len());
+    for chunk in ids.chunks(BATCH_SIZE) {
+        let placeholders: String = vec!["?"; chunk.len()].join(",");
+        let mut params: Vec<Value> = chunk.iter().map(|id| Value::Text(id.to_hex())).collect();
+        let mut cached;
+        let mut uncached;
+        // The prefilter is built from the filters: don't churn the statement cache with it
+        let stmt: &mut Statement = match prefilter {
+            Some((prefilter, prefilter_params)) => {
+                params.extend(prefilter_params.iter().cloned());
+                uncached = conn.prepare(&format!(
+                    "SELECT event, compression, format FROM events WHERE event_id IN ({placeholders}) AND ({prefilter});"
+                ))?;
+                &mut uncached
+            }
+            None => {
+                cached = conn.prepare_cached(&format!(
+                    "SELECT event, compression, format FROM events WHERE event_id IN ({placeholders});"
+                ))?;
+                &mut cached
+            }
+        };
+        let mut rows = stmt.query(params_from_iter(params))?;
+        while let Ok(Some(row)) = rows.next() {
+            match decode_event::<Event>(row.get(0)?, row.get(1)?, row.get(2)?) {
+                Ok(event) => {
+                    events.insert(event.id(), event);
+                }
+                Err(e) if strict => return Err(e),
+                Err(e) => {
+                    tracing::error!("Impossible to decode event: {e}")
+                }
+            }
+        }
+    }
+    let events: Vec<Event> = ids.iter().filter_map(|id| events.remove(id)).collect();
+
+    // Rows deleted (or corrupted) out of band
+    if prefilter.is_none() && events.len() < ids.len() {
+        tracing::warn!(
+            "{} indexed events not loaded: use `reindex` if the database was modified externally",
+            ids.len() - events.len()
+        );
+    }
+
+    Ok(events)
+}
+
+/// Insert an already encoded event, returning `false` if it was already stored
+fn insert_event(
+    conn: &Connection,
+    event: &Event,
+    value: Vec<u8>,
+    format: StorageFormat,
+    compression: Option<i32>,
+) -> Result<bool, Error> {
+    let event_id: String = event.id().to_hex();
+    let (value, compression) = compression::compress(value, compression)?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO events (event_id, event, compression, format, kind, author, created_at, expiration, content_len) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);",
+    )?;
+    let inserted: bool = stmt.execute((
+        &event_id,
+        value,
+        compression,
+        format.flag(),
+        event.kind().as_u32(),
+        event.author().to_hex(),
+        event.created_at().as_u64(),
+        event.expiration().map(|t| t.as_u64()),
+        event.content().len(),
+    ))? > 0;
+
+    if inserted {
+        insert_tags(conn, &event_id, event)?;
+        insert_coordinate(conn, event)?;
+
+        #[cfg(feature = "fts")]
+        fts::insert(conn, &event_id, event.content())?;
+    }
+
+    Ok(inserted)
+}
+
+/// Index the single-letter tags of the event (only their first value, like the indexes)
+pub(crate) fn insert_tags(conn: &Connection, event_id: &str, event: &Event) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO event_tags (event_id, tag_name, tag_value) VALUES (?, ?, ?);",
+    )?;
+    for tag in event.iter_tags() {
+        if let (Some(name), Some(value)) = (tag.single_letter_tag(), tag.content()) {
+            stmt.execute((event_id, name.as_char().to_string(), value.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Set the event as the current one of its coordinate, unless a newer one is already stored
+///
+/// With the same timestamp, the lowest ID is kept (NIP-01).
+pub(crate) fn insert_coordinate(conn: &Connection, event: &Event) -> Result<(), Error> {
+    let identifier: &str = if event.is_replaceable() {
+        ""
+    } else if event.is_parameterized_replaceable() {
+        event.identifier().unwrap_or_default()
+    } else {
+        return Ok(());
+    };
+
+    upsert_coordinate(
+        conn,
+        event.kind().as_u32(),
+        &event.author().to_hex(),
+        identifier,
+        &event.id().to_hex(),
+        event.created_at().as_u64(),
+    )
+}
+
+/// Point the coordinate to the event, unless it already points to a newer one
+fn upsert_coordinate(
+    conn: &Connection,
+    kind: u32,
+    author: &str,
+    identifier: &str,
+    event_id: &str,
+    created_at: u64,
+) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO event_coordinates (kind, author, identifier, event_id, created_at) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (kind, author, identifier) DO UPDATE SET event_id = excluded.event_id, created_at = excluded.created_at
+        WHERE excluded.created_at > event_coordinates.created_at
+        OR (excluded.created_at = event_coordinates.created_at AND excluded.event_id < event_coordinates.event_id);",
+    )?;
+    stmt.execute((kind, author, identifier, event_id, created_at))?;
+    Ok(())
+}
+
+/// Find the stored versions of the replaceable (and parameterized replaceable) events superseded
+/// by a newer one, fixing the coordinates of the newest ones
+///
+/// Returns the IDs of the superseded versions (not deleted yet).
+fn superseded_events(conn: &Connection) -> Result<Vec<EventId>, Error> {
+    // Replaceable kinds, as defined by `Kind` (`true` if parameterized)
+    let mut kinds: HashMap<u32, bool> = HashMap::new();
+    {
+        let mut stmt =
+            conn.prepare_cached("SELECT DISTINCT kind FROM events WHERE kind IS NOT NULL;")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let kind: u32 = row.get(0)?;
+            let k: Kind = Kind::from(kind as u64);
+            if k.is_replaceable() || k.is_parameterized_replaceable() {
+                kinds.insert(kind, k.is_parameterized_replaceable());
+            }
+        }
+    }
+
+    if kinds.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Newest version of each coordinate (ties broken by the lowest ID, like the indexes)
+    type Version = (u64, String);
+    let mut newest: HashMap<(u32, String, String), Version> = HashMap::new();
+    let mut superseded: Vec<EventId> = Vec::new();
+    let placeholders: String = vec!["?"; kinds.len()].join(",");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT kind, author, event_id, created_at, (SELECT tag_value FROM event_tags t WHERE t.event_id = e.event_id AND t.tag_name = 'd' ORDER BY t.rowid LIMIT 1) FROM events e WHERE kind IN ({placeholders});"
+    ))?;
+    let mut rows = stmt.query(params_from_iter(kinds.keys()))?;
+    while let Some(row) = rows.next()? {
+        let kind: u32 = row.get(0)?;
+        let author: String = row.get(1)?;
+        let event_id: String = row.get(2)?;
+        let created_at: u64 = row.get(3)?;
+        let identifier: String = if kinds.get(&kind).copied().unwrap_or(false) {
+            row.get::<_, Option<String>>(4)?.unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let version: Version = (created_at, event_id);
+        match newest.get_mut(&(kind, author.clone(), identifier.clone())) {
+            Some(current) => {
+                let is_newer: bool =
+                    version.0 > current.0 || (version.0 == current.0 && version.1 < current.1);
+                let older: Version = if is_newer {
+                    std::mem::replace(current, version)
+                } else {
+                    version
+                };
+                superseded.push(EventId::from_hex(older.1)?);
+            }
+            None => {
+                newest.insert((kind, author, identifier), version);
+            }
+        }
+    }
+
+    for ((kind, author, identifier), (created_at, event_id)) in newest.into_iter() {
+        upsert_coordinate(conn, kind, &author, &identifier, &event_id, created_at)?;
+    }
+
+    Ok(superseded)
+}
+
+fn event_exists(conn: &Connection, event_id: &EventId) -> Result<bool, Error> {
+    let mut stmt =
+        conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM events WHERE event_id = ? LIMIT 1);")?;
+    let mut rows = stmt.query([event_id.to_hex()])?;
+    let exists: u8 = match rows.next()? {
+        Some(row) => row.get(0)?,
+        None => 0,
+    };
+    Ok(exists == 1)
+}
+
+/// Set [`EventId`] as seen by relay (ignored if already set)
+fn insert_seen(conn: &Connection, event_id: &EventId, relay_url: &Url) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO event_seen_by_relays (event_id, relay_url, seen_at) VALUES (?, ?, ?);",
+    )?;
+    stmt.execute((
+        event_id.to_hex(),
+        relay_url.to_string(),
+        Timestamp::now().as_u64() as i64,
+    ))?;
+    Ok(())
+}
+
+/// Delete all the events and the relays that have seen them
+///
+/// The seen relays aren't deleted by a cascade: see [`delete_events`].
+fn delete_all_events(conn: &Connection) -> Result<(), Error> {
+    // The tags and coordinates would be deleted by the cascade, but clearing them first keeps
+    // the cascade from looking them up for every event
+    conn.execute_batch(
+        "DELETE FROM event_tags; DELETE FROM event_coordinates; DELETE FROM events; DELETE FROM event_seen_by_relays; DELETE FROM deleted_events;",
+    )?;
+    #[cfg(feature = "fts")]
+    conn.execute_batch("DELETE FROM events_fts;")?;
+    Ok(())
+}
+
+/// Record the events deleted with NIP-09 (by a deletion event or with
+/// [`SQLiteDatabase::delete_by_ids`]), restored in the indexes at startup
+///
+/// The replaced events, or the ones deleted by filter or by the retention, aren't recorded:
+/// they would make the table grow with every write. Unlike the comment of the
+/// `009_deleted_events.sql` migration says, they're deleted in memory only until the restart.
+fn insert_tombstones(conn: &Connection, ids: &[EventId]) -> Result<(), Error> {
+    let now: u64 = Timestamp::now().as_u64();
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO deleted_events (event_id, deleted_at) VALUES (?, ?);",
+    )?;
+    for id in ids.iter() {
+        stmt.execute((id.to_hex(), now))?;
+    }
+    Ok(())
+}
+
+/// Delete events, and the relays that have seen them, by [`EventId`]
+///
+/// The tags and coordinates are deleted by the cascade, or by hand if the foreign keys are
+/// disabled (see [`SQLiteOptions::foreign_keys`]). The seen relays are always deleted by hand:
+/// they're recorded also for the events that aren't stored (i.e. ephemeral, rejected or not
+/// received yet), so `event_seen_by_relays` can't reference `events`.
+///
+/// Returns the number of deleted events.
+fn delete_events(conn: &Connection, ids: &[EventId]) -> Result<usize, Error> {
+    let foreign_keys: bool = foreign_keys(conn)?;
+    let mut deleted: usize = 0;
+    for chunk in ids.chunks(DELETE_BATCH_SIZE) {
+        let placeholders: String = vec!["?"; chunk.len()].join(",");
+        let ids: Vec<String> = chunk.iter().map(|id| id.to_hex()).collect();
+        if !foreign_keys {
+            conn.prepare_cached(&format!(
+                "DELETE FROM event_tags WHERE event_id IN ({placeholders});"
+            ))?
+            .execute(params_from_iter(ids.iter()))?;
+            conn.prepare_cached(&format!(
+                "DELETE FROM event_coordinates WHERE event_id IN ({placeholders});"
+            ))?
+            .execute(params_from_iter(ids.iter()))?;
+        }
+        deleted += conn
+            .prepare_cached(&format!(
+                "DELETE FROM events WHERE event_id IN ({placeholders});"
+            ))?
+            .execute(params_from_iter(ids.iter()))?;
+        conn.prepare_cached(&format!(
+            "DELETE FROM event_seen_by_relays WHERE event_id IN ({placeholders});"
+        ))?
+        .execute(params_from_iter(ids.iter()))?;
+        #[cfg(feature = "fts")]
+        conn.prepare_cached(&format!(
+            "DELETE FROM events_fts WHERE event_id IN ({placeholders});"
+        ))?
+        .execute(params_from_iter(ids.iter()))?;
+    }
+    Ok(deleted)
+}
+
+/// Check if the foreign keys are enforced on this connection
+fn foreign_keys(conn: &Connection) -> Result<bool, Error> {
+    Ok(conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?)
+}
+
+/// Delete the events that can't be decoded, by `rowid` (their `event_id` may be corrupt too)
+fn delete_corrupt_events(conn: &Connection, rows: &[(i64, String)]) -> Result<(), Error> {
+    let mut stmt = conn.prepare("DELETE FROM events WHERE rowid = ?;")?;
+    let mut seen_stmt = conn.prepare("DELETE FROM event_seen_by_relays WHERE event_id = ?;")?;
+    #[cfg(feature = "fts")]
+    let mut fts_stmt = conn.prepare("DELETE FROM events_fts WHERE event_id = ?;")?;
+    if !foreign_keys(conn)? {
+        let mut tags_stmt = conn.prepare("DELETE FROM event_tags WHERE event_id = ?;")?;
+        let mut coords_stmt = conn.prepare("DELETE FROM event_coordinates WHERE event_id = ?;")?;
+        for (_, event_id) in rows.iter() {
+            tags_stmt.execute([event_id])?;
+            coords_stmt.execute([event_id])?;
+        }
+    }
+    for (rowid, event_id) in rows.iter() {
+        stmt.execute([rowid])?;
+        seen_stmt.execute([event_id])?;
+        #[cfg(feature = "fts")]
+        fts_stmt.execute([event_id])?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl NostrDatabase for SQLiteDatabase {
+    type Err = Error;
+
+    fn backend(&self) -> Backend {
+        Backend::SQLite
+    }
+
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn save_event(&self, event: &Event) -> Result<bool, Self::Err> {
+        let outcome: SaveOutcome = self.save_event_outcome(event).await?;
+        Ok(matches!(
+            outcome,
+            SaveOutcome::Stored | SaveOutcome::Replaced
+        ))
+    }
+
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn bulk_import(&self, events: BTreeSet<Event>) -> Result<(), Self::Err> {
+        let len: usize = events.len();
+        self.metrics
+            .measure(
+                metrics::Operation::BulkImport,
+                async move {
+                    self.check_writable()?;
+
+                    self.store_events(events).await?;
+                    self.import_checkpoint().await?;
+                    Ok(())
+                },
+                move |_| len,
+            )
+            .await
+    }
+
+    /// Check if the event has already been saved, or deleted (NIP-09)
+    ///
+    /// Returns `true` for a deleted event too, without checking the `events` table, so that it
+    /// isn't saved (and fetched) again. To only check the stored events, use
+    /// [`SQLiteDatabase::is_stored`].
+    async fn has_event_already_been_saved(&self, event_id: &EventId) -> Result<bool, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::HasEventAlreadyBeenSaved,
+                async move {
+                    self.ensure_indexes().await?;
+                    if self.indexes.has_event_id_been_deleted(event_id).await {
+                        Ok(true)
+                    } else {
+                        let event_id: EventId = *event_id;
+                        self.pool
+                            .interact_read(move |conn| event_exists(conn, &event_id))
+                            .await?
+                    }
+                },
+                |found| *found as usize,
+            )
+            .await
+    }
+
+    async fn has_event_already_been_seen(&self, event_id: &EventId) -> Result<bool, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::HasEventAlreadyBeenSeen,
+                async move {
+                    let event_id: String = event_id.to_hex();
+                    self.pool
+                        .interact_read(move |conn| {
+                            let mut stmt = conn.prepare_cached(
+                                "SELECT EXISTS(SELECT 1 FROM event_seen_by_relays WHERE event_id = ? LIMIT 1);",
+                            )?;
+                            let mut rows = stmt.query([event_id])?;
+                            let exists: u8 = match rows.next()? {
+                                Some(row) => row.get(0)?,
+                                None => 0,
+                            };
+                            Ok(exists == 1)
+                        })
+                        .await?
+                },
+                |found| *found as usize,
+            )
+            .await
+    }
+
+    async fn has_event_id_been_deleted(&self, event_id: &EventId) -> Result<bool, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::HasEventIdBeenDeleted,
+                async move {
+                    self.ensure_indexes().await?;
+                    Ok(self.indexes.has_event_id_been_deleted(event_id).await)
+                },
+                |found| *found as usize,
+            )
+            .await
+    }
+
+    async fn has_coordinate_been_deleted(
+        &self,
+        coordinate: &Coordinate,
+        timestamp: Timestamp,
+    ) -> Result<bool, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::HasCoordinateBeenDeleted,
+                async move {
+                    self.ensure_indexes().await?;
+                    Ok(self
+                        .indexes
+                        .has_coordinate_been_deleted(coordinate, timestamp)
+                        .await)
+                },
+                |found| *found as usize,
+            )
+            .await
+    }
+
+    async fn event_id_seen(&self, event_id: EventId, relay_url: Url) -> Result<(), Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::EventIdSeen,
+                async move {
+                    self.check_writable()?;
+
+                    self.pool
+                        .interact(move |conn| insert_seen(conn, &event_id, &relay_url))
+                        .await??;
+                    self.count_seen(1).await
+                },
+                |_| 1,
+            )
+            .await
+    }
+
+    async fn event_seen_on_relays(
+        &self,
+        event_id: EventId,
+    ) -> Result<Option<HashSet<Url>>, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::EventSeenOnRelays,
+                async move {
+                    self.pool
+                        .interact_read(move |conn| {
+                            let mut stmt = conn.prepare_cached(
+                                "SELECT relay_url FROM event_seen_by_relays WHERE event_id = ?;",
+                            )?;
+                            let mut rows = stmt.query([event_id.to_hex()])?;
+                            let mut relays = HashSet::new();
+                            while let Ok(Some(row)) = rows.next() {
+                                let url: String = row.get(0)?;
+                                relays.insert(Url::parse(&url)?);
+                            }
+                            if relays.is_empty() {
+                                Ok(None)
+                            } else {
+                                Ok(Some(relays))
+                            }
+                        })
+                        .await?
+                },
+                |relays| relays.as_ref().map_or(0, |r| r.len()),
+            )
+            .await
+    }
+
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn event_by_id(&self, event_id: EventId) -> Result<Event, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::EventById,
+                async move {
+                    self.event_by_id_opt(event_id)
+                        .await?
+                        .ok_or_else(|| Error::NotFound("event".into()))
+                },
+                |_| 1,
+            )
+            .await
+    }
+
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn count(&self, filters: Vec<Filter>) -> Result<usize, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::Count,
+                async move {
+                    self.ensure_indexes().await?;
+                    Ok(self.indexes.count(filters).await)
+                },
+                |count| *count,
+            )
+            .await
+    }
+
+    /// The `limit` of each filter keeps the most recent matching events (NIP-01), before merging
+    /// the results of the filters. Without search filters, the events are sorted and limited by
+    /// SQL; otherwise they're resolved by the indexes.
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn query(&self, filters: Vec<Filter>, order: Order) -> Result<Vec<Event>, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::Query,
+                async move {
+                    self.ensure_indexes().await?;
+                    let strict: bool = self.opts.strict_decode;
+
+                    // Sorted and limited by SQL, without resolving all the matching IDs first
+                    if !filters.is_empty() && filters.iter().all(is_sql_filter) {
+                        let (sql, params) =
+                            sorted_query(&filters, order, self.opts.store_ephemeral);
+                        return self
+                            .pool
+                            .interact_read(move |conn| select_events(conn, &sql, params, strict))
+                            .await?;
+                    }
+
+                    let prefilter: Option<(String, Vec<Value>)> = prefilter_condition(&filters);
+                    let ids: Vec<EventId> = self.indexes.query(filters, order).await;
+                    self.pool
+                        .interact_read(move |conn| {
+                            load_events(conn, &ids, prefilter.as_ref(), strict)
+                        })
+                        .await?
+                },
+                |events| events.len(),
+            )
+            .await
+    }
+
+    async fn event_ids_by_filters(
+        &self,
+        filters: Vec<Filter>,
+        order: Order,
+    ) -> Result<Vec<EventId>, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::EventIdsByFilters,
+                async move {
+                    self.ensure_indexes().await?;
+                    Ok(self.indexes.query(filters, order).await)
+                },
+                |ids| ids.len(),
+            )
+            .await
+    }
+
+    async fn negentropy_items(
+        &self,
+        filter: Filter,
+    ) -> Result<Vec<(EventId, Timestamp)>, Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::NegentropyItems,
+                self.load_negentropy_items(filter, Vec::new()),
+                |items| items.len(),
+            )
+            .await
+    }
+
+    async fn delete(&self, filter: Filter) -> Result<(), Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::Delete,
+                async move {
+                    self.check_writable()?;
+                    self.ensure_indexes().await?;
+
+                    match self.indexes.delete(filter).await {
+                        Some(ids) => {
+                            let ids: Vec<EventId> = ids.into_iter().collect();
+                            self.pool
+                                .interact(move |conn| {
+                                    let tx = conn.transaction()?;
+                                    delete_events(&tx, &ids)?;
+                                    tx.commit()?;
+                                    Ok::<(), Error>(())
+                                })
+                                .await??;
+                        }
+                        None => {
+                            self.pool
+                                .interact(move |conn| {
+                                    let tx = conn.transaction()?;
+                                    delete_all_events(&tx)?;
+                                    tx.commit()?;
+                                    Ok::<(), Error>(())
+                                })
+                                .await??;
+                        }
+                    };
+                    self.touch();
+
+                    Ok(())
+                },
+                |_| 0,
+            )
+            .await
+    }
+
+    async fn wipe(&self) -> Result<(), Self::Err> {
+        self.metrics
+            .measure(
+                metrics::Operation::Wipe,
+                async move {
+                    self.check_writable()?;
+
+                    let opts = self.opts.clone();
+                    self.pool
+                        .interact(move |conn| {
+                            // Reset DB
+                            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, true)?;
+                            conn.execute("VACUUM;", [])?;
+                            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, false)?;
+
+                            // Re-apply options
+                            opts.apply(conn)?;
+
+                            // Execute migrations (and startup pragmas)
+                            migration::run(conn, opts.strict_decode)?;
+                            opts.apply_after_migrations(conn)?;
+                            #[cfg(feature = "fts")]
+                            fts::init(conn, opts.strict_decode)?;
+
+                            // The reset already drops every table, make it explicit for seen relays
+                            conn.execute("DELETE FROM event_seen_by_relays;", [])?;
+
+                            Ok::<(), Error>(())
+                        })
+                        .await??;
+
+                    self.indexes.clear().await;
+                    self.set_indexes_built();
+                    self.touch();
+
+                    Ok(())
+                },
+                |_| 0,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::{EventBuilder, Keys, Tag};
+
+    use super::*;
+    use crate::migration::MigrationError;
+
+    async fn db_with_text_notes(num: u64) -> SQLiteDatabase {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        for i in 0..num {
+            let event = EventBuilder::text_note(format!("Event #{i}"), [])
+                .custom_created_at(Timestamp::from(1_700_000_000 + i))
+                .to_event(&keys)
+                .unwrap();
+            assert!(db.save_event(&event).await.unwrap());
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn test_event_seen_on_relays() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let event_id = EventId::all_zeros();
+
+        // Never seen
+        assert!(db.event_seen_on_relays(event_id).await.unwrap().is_none());
+
+        let url = Url::parse("wss://relay.damus.io").unwrap();
+        db.event_id_seen(event_id, url.clone()).await.unwrap();
+        let relays = db.event_seen_on_relays(event_id).await.unwrap().unwrap();
+        assert_eq!(relays.len(), 1);
+        assert!(relays.contains(&url));
+    }
+
+    #[tokio::test]
+    async fn test_event_ids_seen() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let event_id = EventId::all_zeros();
+        let damus = Url::parse("wss://relay.damus.io").unwrap();
+        let nos = Url::parse("wss://nos.lol").unwrap();
+
+        db.event_ids_seen(&[
+            (event_id, damus.clone()),
+            (event_id, nos.clone()),
+            (event_id, damus.clone()),
+        ])
+        .await
+        .unwrap();
+        let relays = db.event_seen_on_relays(event_id).await.unwrap().unwrap();
+        assert_eq!(relays, HashSet::from([damus, nos]));
+    }
+
+    #[tokio::test]
+    async fn test_seen_removed_with_events() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let url = Url::parse("wss://relay.damus.io").unwrap();
+
+        // Replaced
+        let profile = |ts: u64| {
+            EventBuilder::new(Kind::Metadata, "{}", [])
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let old = profile(1_600_000_000);
+        let new = profile(1_600_000_001);
+        db.save_event_from(&old, &url).await.unwrap();
+        db.save_event_from(&new, &url).await.unwrap();
+        assert!(db.event_seen_on_relays(old.id()).await.unwrap().is_none());
+        assert!(db.event_seen_on_relays(new.id()).await.unwrap().is_some());
+
+        // Deleted, in more than one batch
+        let events: Vec<Event> = (0..DELETE_BATCH_SIZE + 100)
+            .map(|i| {
+                EventBuilder::text_note(format!("Seen #{i}"), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        db.save_events(&events).await.unwrap();
+        let seen: Vec<(EventId, Url)> = events.iter().map(|e| (e.id(), url.clone())).collect();
+        db.event_ids_seen(&seen).await.unwrap();
+        db.delete(Filter::new().kind(Kind::TextNote)).await.unwrap();
+        for event in events.iter() {
+            assert!(db.event_seen_on_relays(event.id()).await.unwrap().is_none());
+        }
+
+        // Only the seen entries of the stored events are left
+        let rows: usize = db
+            .with_read_connection(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM event_seen_by_relays;", [], |row| {
+                    row.get(0)
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+        assert_eq!(
+            db.event_seen_on_relays(new.id()).await.unwrap(),
+            Some(HashSet::from([url]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_from_relay() {
+        let db = db_with_text_notes(5).await;
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        let damus = Url::parse("wss://relay.damus.io").unwrap();
+        let nos = Url::parse("wss://nos.lol").unwrap();
+        db.event_ids_seen(&[
+            (ids[0], damus.clone()),
+            (ids[2], damus.clone()),
+            (ids[3], damus.clone()),
+            (ids[1], nos.clone()),
+        ])
+        .await
+        .unwrap();
+
+        let events = db
+            .events_from_relay(&damus, vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        assert!(events.iter().map(|e| e.id()).eq([ids[0], ids[2], ids[3]]));
+
+        // The limit applies to the events of the relay
+        let events = db
+            .events_from_relay(&damus, vec![Filter::new().limit(2)], Order::Desc)
+            .await
+            .unwrap();
+        assert!(events.iter().map(|e| e.id()).eq([ids[0], ids[2]]));
+
+        let events = db
+            .events_from_relay(&nos, vec![Filter::new().id(ids[0])], Order::Desc)
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_events_in_range() {
+        let db = db_with_text_notes(10).await;
+        let since = Timestamp::from(1_700_000_002);
+        let until = Timestamp::from(1_700_000_005);
+
+        let events = db.events_in_range(since, until, Order::Asc).await.unwrap();
+        let timestamps: Vec<u64> = events.iter().map(|e| e.created_at().as_u64()).collect();
+        assert_eq!(
+            timestamps,
+            vec![1_700_000_002, 1_700_000_003, 1_700_000_004, 1_700_000_005]
+        );
+
+        let events = db.events_in_range(since, until, Order::Desc).await.unwrap();
+        assert_eq!(events.first().unwrap().created_at(), until);
+
+        assert!(db
+            .events_in_range(until, since, Order::Desc)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_with_relays() {
+        let db = db_with_text_notes(3).await;
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        let relay_a = Url::parse("wss://relay.a").unwrap();
+        let relay_b = Url::parse("wss://relay.b").unwrap();
+        db.event_id_seen(ids[0], relay_a.clone()).await.unwrap();
+        db.event_id_seen(ids[0], relay_b.clone()).await.unwrap();
+        db.event_id_seen(ids[1], relay_b.clone()).await.unwrap();
+
+        let results = db
+            .query_with_relays(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        let results: Vec<(EventId, HashSet<Url>)> = results
+            .into_iter()
+            .map(|(e, relays)| (e.id(), relays))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                (ids[0], HashSet::from([relay_a, relay_b.clone()])),
+                (ids[1], HashSet::from([relay_b])),
+                (ids[2], HashSet::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trim_seen() {
+        let db = db_with_text_notes(3).await;
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        let url = Url::parse("wss://relay.a").unwrap();
+        for id in ids.iter() {
+            db.event_id_seen(*id, url.clone()).await.unwrap();
+        }
+
+        assert_eq!(db.trim_seen(1).await.unwrap(), 2);
+        assert_eq!(db.trim_seen(1).await.unwrap(), 0);
+        // The most recently inserted entry is kept
+        assert!(db.event_seen_on_relays(ids[0]).await.unwrap().is_none());
+        assert!(db.event_seen_on_relays(ids[1]).await.unwrap().is_none());
+        assert_eq!(
+            db.event_seen_on_relays(ids[2]).await.unwrap(),
+            Some(HashSet::from([url]))
+        );
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 3);
+
+        // Automatic trimming
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().max_seen_entries(Some(2));
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        for n in 0..5 {
+            let url = Url::parse(&format!("wss://relay{n}.example.com")).unwrap();
+            db.event_id_seen(ids[0], url).await.unwrap();
+        }
+        assert_eq!(
+            db.event_seen_on_relays(ids[0])
+                .await
+                .unwrap()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_with_timeout() {
+        let db = db_with_text_notes(3).await;
+        let events = db
+            .query_with_timeout(vec![Filter::new()], Order::Desc, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            events,
+            db.query(vec![Filter::new()], Order::Desc).await.unwrap()
+        );
+
+        let res = db
+            .query_with_timeout(
+                vec![Filter::new().kind(Kind::TextNote)],
+                Order::Desc,
+                Duration::ZERO,
+            )
+            .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_negentropy_items_into() {
+        let db = db_with_text_notes(3).await;
+        let filter = Filter::new().kind(Kind::TextNote);
+        let mut items: Vec<(EventId, Timestamp)> = vec![(EventId::all_zeros(), Timestamp::now())];
+        db.negentropy_items_into(filter.clone(), &mut items)
+            .await
+            .unwrap();
+        assert_eq!(items, db.negentropy_items(filter.clone()).await.unwrap());
+
+        let capacity: usize = items.capacity();
+        db.negentropy_items_into(filter.limit(1), &mut items)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn test_all_event_ids() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(db.all_event_ids().await.unwrap().is_empty());
+
+        let db = db_with_text_notes(3).await;
+        let mut ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Asc)
+            .await
+            .unwrap();
+        assert_eq!(db.all_event_ids().await.unwrap(), ids);
+
+        db.delete(Filter::new().id(ids[0])).await.unwrap();
+        ids.remove(0);
+        assert_eq!(db.all_event_ids().await.unwrap(), ids);
+    }
+
+    #[tokio::test]
+    async fn test_query_order() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let created_at = Timestamp::from(1_700_000_000);
+        let mut events: Vec<Event> = Vec::new();
+        for i in 0..4 {
+            let event = EventBuilder::text_note(format!("Event #{i}"), [])
+                .custom_created_at(created_at)
+                .to_event(&keys)
+                .unwrap();
+            events.push(event);
+        }
+        let newest = EventBuilder::text_note("Newest", [])
+            .custom_created_at(created_at + 1_u64)
+            .to_event(&keys)
+            .unwrap();
+        for event in events.iter().chain([&newest]) {
+            db.save_event(event).await.unwrap();
+        }
+
+        // Ties broken by ID
+        events.sort_by_key(|e| e.id());
+        let mut desc: Vec<Event> = vec![newest.clone()];
+        desc.extend(events.iter().cloned());
+        let filter = Filter::new().kind(Kind::TextNote);
+        assert_eq!(
+            db.query(vec![filter.clone()], Order::Desc).await.unwrap(),
+            desc
+        );
+        let asc: Vec<Event> = desc.iter().rev().cloned().collect();
+        assert_eq!(
+            db.query(vec![filter.clone()], Order::Asc).await.unwrap(),
+            asc
+        );
+
+        // Same order as the indexes
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![filter.clone()], Order::Desc)
+            .await
+            .unwrap();
+        assert_eq!(ids, desc.iter().map(|e| e.id()).collect::<Vec<_>>());
+
+        // Newest first, for each filter
+        assert_eq!(
+            db.query(vec![filter.clone().limit(2)], Order::Desc)
+                .await
+                .unwrap(),
+            desc[..2].to_vec()
+        );
+        assert_eq!(
+            db.query(
+                vec![filter.clone().limit(1), Filter::new().id(events[3].id())],
+                Order::Asc
+            )
+            .await
+            .unwrap(),
+            vec![events[3].clone(), newest]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_stream() {
+        use std::pin::Pin;
+
+        use futures_core::Stream;
+
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys_a = Keys::generate();
+        let keys_b = Keys::generate();
+        // More than a batch, with ties on `created_at`
+        for i in 0..250_u64 {
+            let keys: &Keys = if i % 3 == 0 { &keys_b } else { &keys_a };
+            let event = EventBuilder::text_note(format!("Stream #{i}"), [])
+                .custom_created_at(Timestamp::from(1_700_000_000 + i / 4))
+                .to_event(keys)
+                .unwrap();
+            db.save_event(&event).await.unwrap();
+        }
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys_b)
+            .unwrap();
+        db.save_event(&reaction).await.unwrap();
+
+        let filters = vec![
+            Filter::new()
+                .kind(Kind::TextNote)
+                .author(keys_a.public_key())
+                .limit(120),
+            Filter::new().author(keys_b.public_key()),
+        ];
+        for order in [Order::Desc, Order::Asc] {
+            let mut stream = db.query_stream(filters.clone(), order).await;
+            let mut streamed: Vec<Event> = Vec::new();
+            while let Some(event) =
+                std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+            {
+                streamed.push(event.unwrap());
+            }
+            assert_eq!(streamed.len(), 120 + 84 + 1);
+            assert_eq!(streamed, db.query(filters.clone(), order).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_bound_params() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let quoted = EventBuilder::text_note("Quoted", [Tag::Hashtag(String::from("it's"))])
+            .to_event(&keys)
+            .unwrap();
+        let other = EventBuilder::text_note("Other", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&quoted).await.unwrap();
+        db.save_event(&other).await.unwrap();
+
+        // Tag values aren't parsed as SQL
+        let filter = Filter::new().hashtag("it's");
+        assert_eq!(
+            db.query(vec![filter], Order::Desc).await.unwrap(),
+            vec![quoted.clone()]
+        );
+
+        // More IDs than SQLITE_MAX_VARIABLE_NUMBER
+        let mut ids: Vec<EventId> = (0..40_000_u32)
+            .map(|i| EventId::from_hex(format!("{i:064x}")).unwrap())
+            .collect();
+        ids.push(quoted.id());
+        let filter = Filter::new().ids(ids);
+        assert_eq!(
+            db.query(vec![filter.clone()], Order::Desc).await.unwrap(),
+            vec![quoted.clone()]
+        );
+        assert_eq!(
+            db.query(
+                vec![filter.clone(), Filter::new().id(other.id())],
+                Order::Desc
+            )
+            .await
+            .unwrap()
+            .len(),
+            2
+        );
+        assert!(db.explain_query(vec![filter.clone()]).await.is_ok());
+
+        // Prefilter of the events matched by the indexes
+        let search = filter.search("Quoted");
+        assert_eq!(
+            db.query(vec![search], Order::Desc).await.unwrap(),
+            vec![quoted]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_matching() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let metadata = EventBuilder::new(Kind::Metadata, r#"{"name":"Old"}"#, [])
+            .custom_created_at(Timestamp::from(1_700_000_000))
+            .to_event(&keys)
+            .unwrap();
+        let replacement = EventBuilder::new(Kind::Metadata, r#"{"name":"New"}"#, [])
+            .custom_created_at(Timestamp::from(1_700_000_001))
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&metadata).await.unwrap();
+        db.save_event(&replacement).await.unwrap();
+
+        assert_eq!(db.count_matching(Filter::new()).await.unwrap(), 4);
+        assert_eq!(
+            db.count_matching(Filter::new().kind(Kind::Metadata))
+                .await
+                .unwrap(),
+            1
+        );
+        let filter = Filter::new().kind(Kind::TextNote).limit(2);
+        assert_eq!(db.count_matching(filter.clone()).await.unwrap(), 2);
+
+        // Nothing deleted
+        assert_eq!(db.len().await.unwrap(), 4);
+        db.delete(filter).await.unwrap();
+        assert_eq!(db.len().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete_cascade() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let article = EventBuilder::long_form_text_note("Article", [Tag::Identifier("a".into())])
+            .to_event(&keys)
+            .unwrap();
+        let note = EventBuilder::text_note("#nostr", [Tag::Hashtag(String::from("nostr"))])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&article).await.unwrap();
+        db.save_event(&note).await.unwrap();
+
+        let rows = |db: SQLiteDatabase| async move {
+            db.pool
+                .interact(|conn| {
+                    let tags: usize =
+                        conn.query_row("SELECT COUNT(*) FROM event_tags;", [], |row| row.get(0))?;
+                    let coordinates: usize =
+                        conn.query_row("SELECT COUNT(*) FROM event_coordinates;", [], |row| {
+                            row.get(0)
+                        })?;
+                    Ok::<_, Error>((tags, coordinates))
+                })
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(rows(db.clone()).await, (2, 1));
+
+        db.delete(Filter::new().id(article.id())).await.unwrap();
+        assert_eq!(rows(db.clone()).await, (1, 0));
+
+        // Also out of band
+        db.pool
+            .interact(|conn| conn.execute("DELETE FROM events;", []))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rows(db).await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_delete_without_foreign_keys() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().foreign_keys(false);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let keys = Keys::generate();
+        let article = EventBuilder::long_form_text_note("Article", [Tag::Identifier("a".into())])
+            .to_event(&keys)
+            .unwrap();
+        let note = EventBuilder::text_note("#nostr", [Tag::Hashtag(String::from("nostr"))])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&article).await.unwrap();
+        db.save_event(&note).await.unwrap();
+
+        let rows = |db: SQLiteDatabase| async move {
+            db.pool
+                .interact(|conn| {
+                    let foreign_keys: bool = foreign_keys(conn)?;
+                    let tags: usize =
+                        conn.query_row("SELECT COUNT(*) FROM event_tags;", [], |row| row.get(0))?;
+                    let coordinates: usize =
+                        conn.query_row("SELECT COUNT(*) FROM event_coordinates;", [], |row| {
+                            row.get(0)
+                        })?;
+                    Ok::<_, Error>((foreign_keys, tags, coordinates))
+                })
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(rows(db.clone()).await, (false, 2, 1));
+
+        // Deleted by hand
+        db.delete(Filter::new().id(article.id())).await.unwrap();
+        assert_eq!(rows(db.clone()).await, (false, 1, 0));
+        db.delete_by_ids(&[note.id()]).await.unwrap();
+        assert_eq!(rows(db).await, (false, 0, 0));
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_save_event_from() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let relay_a = Url::parse("wss://relay.a.com").unwrap();
+        let relay_b = Url::parse("wss://relay.b.com").unwrap();
+
+        let event = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(db.save_event_from(&event, &relay_a).await.unwrap());
+        // Already stored: the relay is still recorded
+        assert!(!db.save_event_from(&event, &relay_b).await.unwrap());
+
+        let relays = db.event_seen_on_relays(event.id()).await.unwrap().unwrap();
+        assert_eq!(relays, HashSet::from([relay_a.clone(), relay_b]));
+
+        // Rejected
+        let expired = EventBuilder::text_note("Expired", [Tag::Expiration(Timestamp::from(1))])
+            .to_event(&keys)
+            .unwrap();
+        assert!(!db.save_event_from(&expired, &relay_a).await.unwrap());
+        assert!(db
+            .event_seen_on_relays(expired.id())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        let db = db_with_text_notes(3).await;
+
+        let task = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.pool
+                    .interact_read(|conn| {
+                        conn.query_row(
+                            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c;",
+                            [],
+                            |row| row.get::<_, i64>(0),
+                        )
+                        .map_err(Error::from)
+                    })
+                    .await
+            })
+        };
+
+        // Retry until the statement is running
+        while !task.is_finished() {
+            db.cancel_all();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let res = task.await.unwrap().unwrap();
+        assert!(matches!(res, Err(Error::Interrupted)));
+
+        // Next operations aren't affected
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_content_len() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert_eq!(db.total_content_bytes().await.unwrap(), 0);
+        assert!(db.largest_events(10).await.unwrap().is_empty());
+
+        let keys = Keys::generate();
+        let mut events = Vec::new();
+        for content in ["a", "abcdef", "", "abc"] {
+            let event = EventBuilder::text_note(content, [])
+                .to_event(&keys)
+                .unwrap();
+            assert!(db.save_event(&event).await.unwrap());
+            events.push(event);
+        }
+
+        assert_eq!(db.total_content_bytes().await.unwrap(), 10);
+        assert_eq!(
+            db.largest_events(2).await.unwrap(),
+            vec![(events[1].id(), 6), (events[3].id(), 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explain_query() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+
+        let explained = db
+            .explain_query(vec![Filter::new().kind(Kind::TextNote).limit(10)])
+            .await
+            .unwrap();
+        assert!(explained.starts_with("SELECT event, compression, format FROM events"));
+        assert!(explained.contains("kind IN (?)"));
+        assert!(explained.lines().count() > 1);
+
+        let explained = db
+            .explain_query(vec![Filter::new().search("nostr")])
+            .await
+            .unwrap();
+        assert!(explained.starts_with("-- Matched by the indexes"));
+    }
+
+    #[tokio::test]
+    async fn test_save_event_with() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let event = EventBuilder::text_note("Conflict", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        assert_eq!(
+            db.save_event_with(&event, ConflictMode::Error)
+                .await
+                .unwrap(),
+            SaveOutcome::Stored
+        );
+        assert_eq!(
+            db.save_event_with(&event, ConflictMode::Ignore)
+                .await
+                .unwrap(),
+            SaveOutcome::AlreadyHave
+        );
+        assert!(matches!(
+            db.save_event_with(&event, ConflictMode::Error).await,
+            Err(Error::Conflict(id)) if id == event.id()
+        ));
+
+        // Mark the row, to check that it's rewritten
+        let event_id = event.id().to_hex();
+        db.pool
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE events SET format = 99 WHERE event_id = ?;",
+                    [event_id],
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            db.save_event_with(&event, ConflictMode::Replace)
+                .await
+                .unwrap(),
+            SaveOutcome::AlreadyHave
+        );
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+        assert_eq!(db.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_event_bytes_by_id() {
+        use nostr_database::FlatBufferDecode;
+
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Bytes", [])
+            .to_event(&keys)
+            .unwrap();
+
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(db.event_bytes_by_id(event.id()).await.unwrap().is_none());
+        db.save_event(&event).await.unwrap();
+        let bytes = db.event_bytes_by_id(event.id()).await.unwrap().unwrap();
+        assert_eq!(Event::decode(&bytes).unwrap(), event);
+
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().storage_format(StorageFormat::Json);
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        let bytes = db.event_bytes_by_id(event.id()).await.unwrap().unwrap();
+        assert_eq!(Event::from_json(bytes).unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_compact_replaceable() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let metadata = |name: &str, created_at: u64| {
+            EventBuilder::new(Kind::Metadata, format!(r#"{{"name":"{name}"}}"#), [])
+                .custom_created_at(Timestamp::from(created_at))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let article = |d: &str, created_at: u64| {
+            EventBuilder::new(Kind::LongFormTextNote, "", [Tag::Identifier(d.to_string())])
+                .custom_created_at(Timestamp::from(created_at))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let old = metadata("Old", 1_700_000_000);
+        let new = metadata("New", 1_700_000_001);
+        let old_article = article("a", 1_700_000_000);
+        let new_article = article("a", 1_700_000_001);
+        let other_article = article("b", 1_700_000_000);
+        let note = EventBuilder::text_note("Note", []).to_event(&keys).unwrap();
+
+        // Stored out of band, without the replacement
+        let events = [
+            &old,
+            &new,
+            &old_article,
+            &new_article,
+            &other_article,
+            &note,
+        ];
+        let rows: Vec<(Event, Vec<u8>)> = {
+            let mut fbb = db.fbb.write().await;
+            events
+                .iter()
+                .map(|event| {
+                    let value = encode_event(&mut fbb, event, 70_000, StorageFormat::FlatBuffer);
+                    ((*event).clone(), value)
+                })
+                .collect()
+        };
+        db.pool
+            .interact(move |conn| {
+                for (event, value) in rows.into_iter() {
+                    insert_event(conn, &event, value, StorageFormat::FlatBuffer, None).unwrap();
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(db.len().await.unwrap(), 6);
+
+        assert_eq!(db.compact_replaceable().await.unwrap(), 2);
+        assert_eq!(db.len().await.unwrap(), 4);
+        assert!(!db.is_stored(old.id()).await.unwrap());
+        assert!(!db.is_stored(old_article.id()).await.unwrap());
+        assert!(db.is_stored(new.id()).await.unwrap());
+        assert!(db.is_stored(new_article.id()).await.unwrap());
+        assert!(db.is_stored(other_article.id()).await.unwrap());
+        assert!(db.is_stored(note.id()).await.unwrap());
+
+        // Already compacted
+        assert_eq!(db.compact_replaceable().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_is_stored() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Stored", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(!db.is_stored(event.id()).await.unwrap());
+        assert!(db.save_event(&event).await.unwrap());
+        assert!(db.is_stored(event.id()).await.unwrap());
+
+        // Deleted: still reported as saved, but not stored
+        let deletion = EventBuilder::new(Kind::EventDeletion, "", [Tag::event(event.id())])
+            .to_event(&keys)
+            .unwrap();
+        assert!(db.save_event(&deletion).await.unwrap());
+        assert!(db.has_event_already_been_saved(&event.id()).await.unwrap());
+        assert!(!db.is_stored(event.id()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_operation_error() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        db.pool
+            .interact(|conn| conn.execute_batch("DROP TABLE event_seen_by_relays;"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let err = db
+            .event_seen_on_relays(EventId::all_zeros())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Operation {
+                operation: "event_seen_on_relays",
+                ..
+            }
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("event_seen_on_relays: no such table"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_counts() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let popular = EventId::all_zeros();
+        let other =
+            EventId::from_hex("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        let unseen =
+            EventId::from_hex("0000000000000000000000000000000000000000000000000000000000000002")
+                .unwrap();
+        let damus = Url::parse("wss://relay.damus.io").unwrap();
+        let nos = Url::parse("wss://nos.lol").unwrap();
+        db.event_ids_seen(&[(popular, damus.clone()), (popular, nos), (other, damus)])
+            .await
+            .unwrap();
+
+        assert_eq!(db.relay_count_for(popular).await.unwrap(), 2);
+        assert_eq!(db.relay_count_for(unseen).await.unwrap(), 0);
+        assert_eq!(
+            db.relay_counts(&[popular, other, unseen]).await.unwrap(),
+            HashMap::from([(popular, 2), (other, 1), (unseen, 0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wipe() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let event_id = EventId::all_zeros();
+
+        let url = Url::parse("wss://relay.damus.io").unwrap();
+        db.event_id_seen(event_id, url).await.unwrap();
+        assert!(db.has_event_already_been_seen(&event_id).await.unwrap());
+
+        db.wipe().await.unwrap();
+        assert!(!db.has_event_already_been_seen(&event_id).await.unwrap());
+        assert!(db.event_seen_on_relays(event_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_query_by_kind() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&reaction).await.unwrap();
+
+        let events = db
+            .query(vec![Filter::new().kind(Kind::Reaction)], Order::Desc)
+            .await
+            .unwrap();
+        assert_eq!(events, [reaction]);
+
+        let events = db
+            .query(
+                vec![
+                    Filter::new().kind(Kind::TextNote),
+                    Filter::new().kind(Kind::Reaction),
+                ],
+                Order::Desc,
+            )
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_query_limit() {
+        let db = db_with_text_notes(10).await;
+        let created_at = |events: Vec<Event>| -> BTreeSet<u64> {
+            events.iter().map(|e| e.created_at().as_u64()).collect()
+        };
+
+        // Most recent events
+        let events = db
+            .query(
+                vec![Filter::new().kind(Kind::TextNote).limit(3)],
+                Order::Desc,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            created_at(events),
+            BTreeSet::from([1_700_000_007, 1_700_000_008, 1_700_000_009])
+        );
+
+        // Also in ascending order
+        let events = db
+            .query(vec![Filter::new().limit(2)], Order::Asc)
+            .await
+            .unwrap();
+        assert_eq!(
+            created_at(events),
+            BTreeSet::from([1_700_000_008, 1_700_000_009])
+        );
+
+        // Per filter
+        let events = db
+            .query(
+                vec![
+                    Filter::new().until(Timestamp::from(1_700_000_004)).limit(2),
+                    Filter::new().since(Timestamp::from(1_700_000_005)).limit(1),
+                ],
+                Order::Desc,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            created_at(events),
+            BTreeSet::from([1_700_000_003, 1_700_000_004, 1_700_000_009])
+        );
+
+        assert_eq!(
+            db.count(vec![Filter::new().kind(Kind::TextNote).limit(4)])
+                .await
+                .unwrap(),
+            4
+        );
+        assert!(db
+            .query(vec![Filter::new().limit(0)], Order::Desc)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_event_by_id_opt() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+
+        assert_eq!(
+            db.event_by_id_opt(note.id()).await.unwrap(),
+            Some(note.clone())
+        );
+        assert_eq!(db.event_by_id(note.id()).await.unwrap(), note);
+        assert!(db
+            .event_by_id_opt(EventId::all_zeros())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            db.event_by_id(EventId::all_zeros()).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_event_by_coordinate() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let profile = |ts: u64| {
+            EventBuilder::new(Kind::Metadata, "{}", [])
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let old = profile(1_600_000_000);
+        let new = profile(1_600_000_001);
+        db.save_event(&new).await.unwrap();
+        db.save_event(&old).await.unwrap();
+
+        let coordinate = Coordinate::new(Kind::Metadata, keys.public_key());
+        assert_eq!(
+            db.event_by_coordinate(&coordinate).await.unwrap(),
+            Some(new)
+        );
+
+        let article = EventBuilder::long_form_text_note("Article", [Tag::Identifier("a".into())])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&article).await.unwrap();
+        let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("a");
+        assert_eq!(
+            db.event_by_coordinate(&coordinate).await.unwrap(),
+            Some(article.clone())
+        );
+
+        db.delete(Filter::new().id(article.id())).await.unwrap();
+        assert!(db.event_by_coordinate(&coordinate).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_latest_event_for_coordinate() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let article = |content: &str, ts: u64| {
+            let tags = [
+                Tag::Identifier(String::from("article")),
+                Tag::Expiration(Timestamp::now() + 3600_u64),
+            ];
+            EventBuilder::long_form_text_note(content, tags)
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let old = article("Old", 1_600_000_000);
+        let new = article("New", 1_600_000_001);
+        db.save_event(&old).await.unwrap();
+        db.save_event(&new).await.unwrap();
+
+        let coordinate =
+            Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("article");
+        assert_eq!(
+            db.latest_event_for_coordinate(&coordinate).await.unwrap(),
+            Some(new.clone())
+        );
+        let other = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("other");
+        assert!(db
+            .latest_event_for_coordinate(&other)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Expired, but not deleted yet
+        let event_id = new.id().to_hex();
+        db.pool
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE events SET expiration = 1 WHERE event_id = ?;",
+                    [event_id],
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db.is_stored(new.id()).await.unwrap());
+        assert!(db
+            .latest_event_for_coordinate(&coordinate)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_events_by_rowid() {
+        let db = db_with_text_notes(3).await;
+        let events = db.events_by_rowid(Order::Asc).await.unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].1.content(), "Event #0");
+
+        let (rowid, event) = events.last().unwrap();
+        assert_eq!(db.event_rowid(event.id()).await.unwrap(), Some(*rowid));
+
+        let events = db.events_by_rowid(Order::Desc).await.unwrap();
+        assert_eq!(events[0].0, *rowid);
+    }
+
+    #[tokio::test]
+    async fn test_events_by_ids() {
+        let db = db_with_text_notes(BATCH_SIZE as u64 + 5).await;
+        let mut ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Asc)
+            .await
+            .unwrap();
+        ids.insert(1, EventId::all_zeros());
+
+        let events = db.events_by_ids(&ids).await.unwrap();
+        assert_eq!(events.len(), BATCH_SIZE + 5);
+        assert!(events
+            .iter()
+            .map(|e| e.id())
+            .eq(ids.into_iter().filter(|id| *id != EventId::all_zeros())));
+        assert!(db.events_by_ids(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_by_tag() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let other = Keys::generate();
+        let mention = EventBuilder::text_note("Hi", [Tag::public_key(other.public_key())])
+            .to_event(&keys)
+            .unwrap();
+        let tagged = EventBuilder::text_note("It's #nostr", [Tag::Hashtag(String::from("it's"))])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&mention).await.unwrap();
+        db.save_event(&tagged).await.unwrap();
+
+        let filter = Filter::new().pubkey(other.public_key());
+        assert_eq!(
+            db.query(vec![filter.clone()], Order::Desc).await.unwrap(),
+            [mention.clone()]
+        );
+        assert_eq!(
+            db.negentropy_items(filter.clone()).await.unwrap(),
+            [(mention.id(), mention.created_at())]
+        );
+        assert_eq!(
+            db.negentropy_items(Filter::new().hashtag("it's"))
+                .await
+                .unwrap(),
+            [(tagged.id(), tagged.created_at())]
+        );
+
+        // Removed with the event
+        db.delete(filter).await.unwrap();
+        let tags: usize = db
+            .with_read_connection(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM event_tags;", [], |row| row.get(0))
+            })
+            .await
+            .unwrap();
+        assert_eq!(tags, 1);
+    }
+
+    #[tokio::test]
+    async fn test_which_are_saved() {
+        let db = db_with_text_notes(3).await;
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Asc)
+            .await
+            .unwrap();
+        db.delete(Filter::new().id(ids[0])).await.unwrap();
+
+        let missing = EventId::all_zeros();
+        let saved = db
+            .which_are_saved(&[ids[0], ids[1], missing])
+            .await
+            .unwrap();
+        assert_eq!(saved, HashSet::from([ids[0], ids[1]]));
+        assert!(db.which_are_saved(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_which_are_seen() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let relay_a = Url::parse("wss://relay.a.com").unwrap();
+        let relay_b = Url::parse("wss://relay.b.com").unwrap();
+        let seen = EventId::all_zeros();
+        let unseen =
+            EventId::from_hex("0000000000000000000000000000000000000000000000000000000000000001")
+                .unwrap();
+        db.event_id_seen(seen, relay_a).await.unwrap();
+        db.event_id_seen(seen, relay_b).await.unwrap();
+
+        assert_eq!(
+            db.which_are_seen(&[seen, unseen]).await.unwrap(),
+            HashSet::from([seen])
+        );
+        assert!(db.which_are_seen(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_count_by_kind() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&reaction).await.unwrap();
+
+        let counts = db.count_by_kind().await.unwrap();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [(Kind::TextNote, 3), (Kind::Reaction, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_memory_estimate() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert_eq!(db.index_memory_estimate().await.unwrap(), 0);
+
+        let db = db_with_text_notes(10).await;
+        assert_eq!(db.indexed_event_count().await, 10);
+        assert_eq!(
+            db.index_memory_estimate().await.unwrap(),
+            10 * INDEX_ENTRY_SIZE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_by_author() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+
+        let events = db
+            .query(vec![Filter::new().author(keys.public_key())], Order::Desc)
+            .await
+            .unwrap();
+        assert_eq!(events, [note.clone()]);
+
+        let events = db
+            .query(
+                vec![Filter::new().author(keys.public_key()).kind(Kind::TextNote)],
+                Order::Desc,
+            )
+            .await
+            .unwrap();
+        assert_eq!(events, [note]);
+    }
+
+    #[cfg(feature = "fts")]
+    #[tokio::test]
+    async fn test_search() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Nostr and SQLite", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+
+        assert_eq!(db.search("sqlite", 10).await.unwrap(), [note.clone()]);
+        assert_eq!(db.search("event", 10).await.unwrap().len(), 3);
+        assert_eq!(db.search("event", 2).await.unwrap().len(), 2);
+
+        // Deleted events are removed from the index
+        db.delete(Filter::new().id(note.id())).await.unwrap();
+        assert!(db.search("sqlite", 10).await.unwrap().is_empty());
+    }
+
+    #[cfg(not(feature = "fts"))]
+    #[tokio::test]
+    async fn test_search_unsupported() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(matches!(
+            db.search("nostr", 10).await,
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    fn temp_db_path() -> PathBuf {
+        let name: String = format!("nostr-sqlite-{}.db", Keys::generate().public_key());
+        std::env::temp_dir().join(name)
+    }
+
+    fn remove_db_files(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_path() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(db.path().is_none());
+
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(db.path(), Some(path.as_path()));
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_journal_mode() {
+        let journal_mode = |db: SQLiteDatabase| async move {
+            db.pool
+                .interact(|conn| {
+                    conn.pragma_query_value(None, "journal_mode", |row| row.get::<_, String>(0))
+                })
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        for (mode, expected) in [(JournalMode::Wal, "wal"), (JournalMode::Delete, "delete")] {
+            let path = temp_db_path();
+            let opts = SQLiteOptions::new().journal_mode(mode);
+            let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+            assert_eq!(journal_mode(db.clone()).await, expected);
+
+            // Re-applied after the reset
+            db.wipe().await.unwrap();
+            assert_eq!(journal_mode(db.clone()).await, expected);
+
+            drop(db);
+            remove_db_files(&path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_statement_cache_capacity() {
+        assert_eq!(SQLiteOptions::new().statement_cache_capacity, 64);
+
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..5)
+            .map(|i| {
+                EventBuilder::text_note(format!("Cached #{i}"), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        for capacity in [0, 1, 128] {
+            let opts = SQLiteOptions::new().statement_cache_capacity(capacity);
+            assert_eq!(opts.statement_cache_capacity, capacity);
+
+            // The cached statements are evicted (and prepared again) by the small caches
+            let path = temp_db_path();
+            let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+            for event in events.iter() {
+                db.save_event(event).await.unwrap();
+                db.event_id_seen(event.id(), Url::parse("wss://relay.damus.io").unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(db.event_by_id(event.id()).await.unwrap(), event.clone());
+            }
+            let filter = Filter::new().author(keys.public_key());
+            assert_eq!(db.count(vec![filter.clone()]).await.unwrap(), events.len());
+            assert_eq!(
+                db.query(vec![filter], Order::Desc).await.unwrap().len(),
+                events.len()
+            );
+
+            drop(db);
+            remove_db_files(&path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_read_only() {
+        let path = temp_db_path();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Read-only", [])
+            .to_event(&keys)
+            .unwrap();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.save_event(&event).await.unwrap();
+        drop(db);
+
+        // The indexes are loaded from the stored events
+        let db = SQLiteDatabase::open_read_only(&path).await.unwrap();
+        let filter = Filter::new().author(keys.public_key());
+        assert_eq!(
+            db.query(vec![filter.clone()], Order::Desc).await.unwrap(),
+            vec![event.clone()]
+        );
+        assert_eq!(db.count(vec![filter.clone()]).await.unwrap(), 1);
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+
+        let other = EventBuilder::text_note("Rejected", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(matches!(db.save_event(&other).await, Err(Error::ReadOnly)));
+        assert!(matches!(
+            db.bulk_import(BTreeSet::from([other])).await,
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(
+            db.delete(filter.clone()).await,
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(db.wipe().await, Err(Error::ReadOnly)));
+        assert_eq!(db.count(vec![filter]).await.unwrap(), 1);
+        drop(db);
+
+        // Not migrated: the schema version must be the latest one
+        let set_version = |version: usize| {
+            let conn = Connection::open(&path).unwrap();
+            conn.pragma_update(None, "user_version", version).unwrap();
+        };
+        set_version(migration::DB_VERSION - 1);
+        assert!(matches!(
+            SQLiteDatabase::open_read_only(&path).await,
+            Err(Error::Migration(
+                migration::MigrationError::OlderDbVersion { .. }
+            ))
+        ));
+        set_version(migration::DB_VERSION + 1);
+        assert!(matches!(
+            SQLiteDatabase::open_read_only(&path).await,
+            Err(Error::IncompatibleSchema { .. })
+        ));
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().timeout(Some(Duration::from_millis(100)));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+
+        let started = Instant::now();
+        let res = db
+            .pool
+            .interact(|_| std::thread::sleep(Duration::from_millis(500)))
+            .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // The writer is still held by the blocking closure
+        assert!(matches!(
+            db.pool.interact(|_| ()).await,
+            Err(Error::Timeout)
+        ));
+
+        // Usable again once released
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let event = EventBuilder::text_note("After timeout", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(db.save_event(&event).await.unwrap());
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_busy_timeout() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().busy_timeout(Duration::from_millis(300));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let write = |db: SQLiteDatabase| async move {
+            db.pool
+                .interact(|conn| conn.execute_batch("BEGIN IMMEDIATE; COMMIT;"))
+                .await
+                .unwrap()
+        };
+
+        // Write lock held by another connection
+        let other = Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE;").unwrap();
+
+        let started = Instant::now();
+        let err = write(db.clone()).await.unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy)
+        );
+
+        // Released while waiting
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            other.execute_batch("COMMIT;").unwrap();
+        });
+        write(db.clone()).await.unwrap();
+        release.join().unwrap();
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_cache_and_mmap_size() {
+        const READERS: usize = 3;
+
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new()
+            .cache_size(4096)
+            .mmap_size(1024 * 1024)
+            .read_connections(READERS);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let sizes = |conn: &Connection| -> rusqlite::Result<(i64, u64)> {
+            let cache_size: i64 = conn.pragma_query_value(None, "cache_size", |row| row.get(0))?;
+            let mmap_size: u64 = conn.pragma_query_value(None, "mmap_size", |row| row.get(0))?;
+            Ok((cache_size, mmap_size))
+        };
+
+        let writer = db.pool.interact(move |conn| sizes(conn)).await.unwrap();
+        assert_eq!(writer.unwrap(), (-4096, 1024 * 1024));
+
+        // Held at the same time, so each call gets a different reader
+        let barrier = Arc::new(std::sync::Barrier::new(READERS));
+        let mut tasks = Vec::new();
+        for _ in 0..READERS {
+            let db = db.clone();
+            let barrier = barrier.clone();
+            tasks.push(tokio::spawn(async move {
+                db.with_read_connection(move |conn| {
+                    barrier.wait();
+                    Ok((
+                        conn.is_readonly(rusqlite::DatabaseName::Main)?,
+                        sizes(conn)?,
+                    ))
+                })
+                .await
+                .unwrap()
+            }));
+        }
+        for task in tasks.into_iter() {
+            assert_eq!(task.await.unwrap(), (true, (-4096, 1024 * 1024)));
+        }
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_open_in_memory() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(db.path().is_none());
+        assert_eq!(
+            db.schema_version().await.unwrap(),
+            migration::DB_VERSION as u32
+        );
+
+        let event = EventBuilder::text_note("In memory", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(db.save_event(&event).await.unwrap());
+        assert_eq!(
+            db.query(vec![Filter::new().id(event.id())], Order::Desc)
+                .await
+                .unwrap(),
+            vec![event.clone()]
+        );
+
+        // The reads use the same connection, so the same database
+        let event_id: String = event.id().to_hex();
+        let stored: bool = db
+            .with_read_connection(move |conn| {
+                conn.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM events WHERE event_id = ?);",
+                    [event_id],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert!(stored);
+
+        // Independent databases
+        let other = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(other.event_by_id(event.id()).await.is_err());
+        assert!(other
+            .query(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_len() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert_eq!(db.len().await.unwrap(), 0);
+        assert!(db.is_empty().await.unwrap());
+
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..3)
+            .map(|i| {
+                EventBuilder::text_note(format!("Len #{i}"), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        for event in events.iter() {
+            db.save_event(event).await.unwrap();
+        }
+        // Duplicates aren't counted
+        db.save_event(&events[0]).await.unwrap();
+        assert_eq!(db.len().await.unwrap(), 3);
+        assert!(!db.is_empty().await.unwrap());
+
+        db.delete(Filter::new().id(events[0].id())).await.unwrap();
+        assert_eq!(db.len().await.unwrap(), 2);
+
+        db.wipe().await.unwrap();
+        assert_eq!(db.len().await.unwrap(), 0);
+        assert!(db.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_database_size() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let page_size: u64 = db
+            .pool
+            .interact(|conn| conn.pragma_query_value(None, "page_size", |row| row.get(0)))
+            .await
+            .unwrap()
+            .unwrap();
+        let empty: u64 = db.database_size().await.unwrap();
+        assert!(empty > 0);
+        assert_eq!(empty % page_size, 0);
+        assert_eq!(db.freelist_count().await.unwrap(), 0);
+
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..200)
+            .map(|i| {
+                EventBuilder::text_note(format!("{i}").repeat(500), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        db.save_events(&events).await.unwrap();
+        let full: u64 = db.database_size().await.unwrap();
+        assert!(full > empty);
+        assert_eq!(db.freelist_count().await.unwrap(), 0);
+
+        // Not shrunk: the pages of the deleted rows become free pages
+        db.delete(Filter::new().kind(Kind::TextNote)).await.unwrap();
+        assert!(db.database_size().await.unwrap() >= full);
+        assert!(db.freelist_count().await.unwrap() > 0);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_vacuum() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..200)
+            .map(|i| {
+                EventBuilder::text_note(format!("{i}").repeat(500), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        db.save_events(&events).await.unwrap();
+        let kept = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&kept).await.unwrap();
+        db.delete(Filter::new().kind(Kind::TextNote)).await.unwrap();
+
+        let size: u64 = db.database_size().await.unwrap();
+        assert!(db.freelist_count().await.unwrap() > 0);
+        db.vacuum().await.unwrap();
+        assert_eq!(db.freelist_count().await.unwrap(), 0);
+        assert!(db.database_size().await.unwrap() < size);
+
+        // Stored events and indexes untouched
+        assert_eq!(db.len().await.unwrap(), 1);
+        assert_eq!(
+            db.query(vec![Filter::new().author(keys.public_key())], Order::Desc)
+                .await
+                .unwrap(),
+            vec![kept]
+        );
+        drop(db);
+
+        let db = SQLiteDatabase::open_read_only(&path).await.unwrap();
+        assert!(matches!(db.vacuum().await, Err(Error::ReadOnly)));
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_schema_version() {
+        let latest: u32 = migration::DB_VERSION as u32;
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(db.schema_version().await.unwrap(), latest);
+        drop(db);
+
+        let db = SQLiteDatabase::open_read_only(&path).await.unwrap();
+        assert_eq!(db.schema_version().await.unwrap(), latest);
+        drop(db);
+
+        // Created by a newer version
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", latest + 1)
+            .unwrap();
+        drop(conn);
+        match SQLiteDatabase::open(&path).await {
+            Err(Error::IncompatibleSchema { found, supported }) => {
+                assert_eq!(found, latest + 1);
+                assert_eq!(supported, latest);
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        remove_db_files(&path);
+    }
+
+    async fn synchronous_level(db: &SQLiteDatabase) -> u8 {
+        db.pool
+            .interact(|conn| conn.pragma_query_value(None, "synchronous", |row| row.get(0)))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_with_synchronous() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().synchronous(Synchronous::Full);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        // Not overridden by the migrations
+        assert_eq!(synchronous_level(&db).await, 2);
+
+        let (inside, restored) = db
+            .with_synchronous(Synchronous::Off, || synchronous_level(&db))
+            .await
+            .unwrap();
+        restored.unwrap();
+        assert_eq!(inside, 0);
+        assert_eq!(synchronous_level(&db).await, 2);
+
+        // Restored in background if cancelled
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(50),
+            db.with_synchronous(Synchronous::Off, std::future::pending::<()>),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        let mut level = synchronous_level(&db).await;
+        for _ in 0..100 {
+            if level == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            level = synchronous_level(&db).await;
+        }
+        assert_eq!(level, 2);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_wal_info() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(db.wal_info().await.unwrap().is_none());
+
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let event = EventBuilder::text_note("WAL", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        let info = db.wal_info().await.unwrap().unwrap();
+        assert!(info.size > 0);
+        assert!(info.autocheckpoint_size > 0);
+
+        db.checkpoint().await.unwrap();
+        assert_eq!(db.wal_info().await.unwrap().unwrap().size, 0);
+        drop(db);
+
+        // Not in WAL mode
+        let opts = SQLiteOptions::new().journal_mode(JournalMode::Delete);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        assert!(db.wal_info().await.unwrap().is_none());
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().checkpoint_interval(Some(Duration::from_secs(3600)));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let event = EventBuilder::text_note("Flush", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        db.flush().await.unwrap();
+
+        // The background task doesn't keep the database open
+        db.close().await.unwrap();
+        remove_db_files(&path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().file_mode(Some(0o600));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let event = EventBuilder::text_note("Private", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        let mut wal = path.clone().into_os_string();
+        wal.push("-wal");
+        assert_eq!(mode(Path::new(&wal)), 0o600);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_page_size() {
+        let page_size = |db: SQLiteDatabase| async move {
+            db.pool
+                .interact(|conn| {
+                    conn.pragma_query_value(None, "page_size", |row| row.get::<_, u32>(0))
+                })
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().page_size(Some(8192));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        assert_eq!(page_size(db.clone()).await, 8192);
+
+        db.set_page_size(16384).await.unwrap();
+        assert_eq!(page_size(db.clone()).await, 16384);
+        assert!(db.wal_info().await.unwrap().is_some());
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_verify_schema() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert!(db.verify_schema().await.unwrap().is_empty());
+
+        db.pool
+            .interact(|conn| conn.execute_batch("DROP INDEX events_kind_index;"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            db.verify_schema().await.unwrap(),
+            vec![String::from("missing index: events_kind_index")]
+        );
+        drop(db);
+
+        // Recreated on open
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert!(db.verify_schema().await.unwrap().is_empty());
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_open_with_progress() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        for i in 0..3 {
+            let event = EventBuilder::text_note(format!("Event #{i}"), [])
+                .to_event(&keys)
+                .unwrap();
+            db.save_event(&event).await.unwrap();
+        }
+        drop(db);
+
+        let calls: std::sync::Mutex<Vec<(usize, usize)>> = std::sync::Mutex::new(Vec::new());
+        let db = SQLiteDatabase::open_with_progress(&path, |loaded, total| {
+            calls.lock().unwrap().push((loaded, total));
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls.lock().unwrap().last(), Some(&(3, 3)));
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 3);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_open_lazy() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Stored", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        drop(db);
+
+        let db = SQLiteDatabase::open_lazy(&path).await.unwrap();
+        assert_eq!(db.indexes.count(vec![Filter::new()]).await, 0);
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+
+        // Built on first use
+        let new = EventBuilder::text_note("New", []).to_event(&keys).unwrap();
+        db.save_event(&new).await.unwrap();
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 2);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let event = EventBuilder::text_note("Close", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+
+        let clone = db.clone();
+        assert!(matches!(db.close().await, Err(Error::StillInUse)));
+        clone.close().await.unwrap();
+
+        // WAL checkpointed on close
+        let mut wal = path.clone().into_os_string();
+        wal.push("-wal");
+        assert!(!Path::new(&wal).exists());
+
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_close_with_busy_reader() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new()
+            .read_connections(1)
+            .timeout(Some(Duration::from_millis(20)));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+
+        // The reader is still held by the blocking task
+        let res = db
+            .pool
+            .interact_read(|_| std::thread::sleep(Duration::from_millis(300)))
+            .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(matches!(db.close().await, Err(Error::StillInUse)));
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.close().await.unwrap();
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_optimize() {
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().optimize_every(Some(2));
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        db.optimize().await.unwrap();
+
+        let keys = Keys::generate();
+        for i in 0..3 {
+            let event = EventBuilder::text_note(format!("Event #{i}"), [])
+                .to_event(&keys)
+                .unwrap();
+            db.save_event(&event).await.unwrap();
+        }
+        assert_eq!(db.writes.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_ids_only_nip09() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let note = |content: &str| {
+            EventBuilder::text_note(content, [])
+                .to_event(&keys)
+                .unwrap()
+        };
+
+        // Replaced, deleted by filter or by the retention
+        let profile = |ts: u64| {
+            EventBuilder::new(Kind::Metadata, "{}", [])
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        db.save_event(&profile(1_600_000_000)).await.unwrap();
+        db.save_event(&profile(1_600_000_001)).await.unwrap();
+        db.save_events(&[profile(1_600_000_002), profile(1_600_000_003)])
+            .await
+            .unwrap();
+        let filtered = note("Deleted by filter");
+        db.save_event(&filtered).await.unwrap();
+        db.delete(Filter::new().id(filtered.id())).await.unwrap();
+        let old = EventBuilder::text_note("Old", [])
+            .custom_created_at(Timestamp::from(1_000))
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&old).await.unwrap();
+        db.delete_older_than(Timestamp::from(1_500_000_000))
+            .await
+            .unwrap();
+        assert!(db.deleted_ids().await.unwrap().is_empty());
+
+        // NIP-09
+        let deletion = |id: EventId| {
+            EventBuilder::new(Kind::EventDeletion, "", [Tag::event(id)])
+                .to_event(&keys)
+                .unwrap()
+        };
+        let a = note("A");
+        let b = note("B");
+        let c = note("C");
+        let d = note("D");
+        db.save_events(&[a.clone(), b.clone(), c.clone(), d.clone()])
+            .await
+            .unwrap();
+        db.save_event(&deletion(a.id())).await.unwrap();
+        db.save_events(&[deletion(b.id())]).await.unwrap();
+        let deletion_c = deletion(c.id());
+        db.transaction(|tx| tx.save_event(deletion_c))
+            .await
+            .unwrap();
+        db.delete_by_ids(&[d.id()]).await.unwrap();
+        assert_eq!(
+            db.deleted_ids().await.unwrap(),
+            HashSet::from([a.id(), b.id(), c.id(), d.id()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deleted_ids() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Deleted", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+        let deletion = EventBuilder::new(Kind::EventDeletion, "", [Tag::event(note.id())])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&deletion).await.unwrap();
+        assert_eq!(db.deleted_ids().await.unwrap(), HashSet::from([note.id()]));
+        drop(db);
+
+        // The deletion survives a restart without the deleted event
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.delete(Filter::new().id(deletion.id())).await.unwrap();
+        drop(db);
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert!(db.has_event_id_been_deleted(&note.id()).await.unwrap());
+        assert!(!db.save_event(&note).await.unwrap());
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_temp_store() {
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().temp_store(TempStore::Memory);
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        let temp_store: i64 = db
+            .with_read_connection(|conn| {
+                conn.pragma_query_value(None, "temp_store", |row| row.get(0))
+            })
+            .await
+            .unwrap();
+        // 2: memory
+        assert_eq!(temp_store, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Removed", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+
+        // Delete out of band
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("DELETE FROM events;", []).unwrap();
+        assert!(db
+            .query(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 1);
+
+        db.reindex().await.unwrap();
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 0);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_from_connection() {
+        let db = SQLiteDatabase::from_connection(
+            Connection::open_in_memory().unwrap(),
+            SQLiteOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(db.path().is_none());
+        assert_eq!(
+            db.schema_version().await.unwrap(),
+            migration::DB_VERSION as u32
+        );
+
+        let path = temp_db_path();
+        let conn = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )
+        .unwrap();
+        let db = SQLiteDatabase::from_connection(conn, SQLiteOptions::default())
+            .await
+            .unwrap();
+        assert!(db.path().is_some());
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[cfg(feature = "load_extension")]
+    #[tokio::test]
+    async fn test_load_extension_error() {
+        let opts = SQLiteOptions::new().extensions(["/nonexistent/extension"]);
+        let res = SQLiteDatabase::new(
+            None,
+            Connection::open_in_memory().unwrap(),
+            Vec::new(),
+            opts,
+            &[],
+            None,
+        )
+        .await;
+        assert!(matches!(res, Err(Error::LoadExtension { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_incremental_vacuum() {
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().auto_vacuum(Some(AutoVacuum::Incremental));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..200)
+            .map(|i| {
+                EventBuilder::text_note(format!("{i}").repeat(500), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        db.save_events(&events).await.unwrap();
+        db.delete(Filter::new().kind(Kind::TextNote)).await.unwrap();
+
+        let free = db.freelist_count().await.unwrap();
+        assert!(free > 2);
+
+        // Nothing released
+        db.incremental_vacuum(Some(0)).await.unwrap();
+        assert_eq!(db.freelist_count().await.unwrap(), free);
+
+        db.incremental_vacuum(Some(2)).await.unwrap();
+        assert_eq!(db.freelist_count().await.unwrap(), free - 2);
+
+        db.incremental_vacuum(None).await.unwrap();
+        assert_eq!(db.freelist_count().await.unwrap(), 0);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_last_mutation() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert!(db.last_mutation().is_none());
+
+        let event = EventBuilder::text_note("Mutation", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        assert!(db.last_mutation().is_some());
+
+        // Changes of other connections
+        let version = db.data_version().await.unwrap();
+        db.save_event(
+            &EventBuilder::text_note("Own", [])
+                .to_event(&Keys::generate())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.data_version().await.unwrap(), version);
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("DELETE FROM events;", []).unwrap();
+        assert_ne!(db.data_version().await.unwrap(), version);
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_backup_to() {
+        let db = db_with_text_notes(5).await;
+        let path = temp_db_path();
+
+        db.backup_to(&path).await.unwrap();
+
+        let backup = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(backup.len().await.unwrap(), db.len().await.unwrap());
+        assert_eq!(
+            backup.count(vec![Filter::new()]).await.unwrap(),
+            db.count(vec![Filter::new()]).await.unwrap()
+        );
+
+        drop(backup);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_backup_to_doesnt_block_writes() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        // Large enough for the copy to take a while
+        db.pool
+            .interact(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE padding (data BLOB); WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 100) INSERT INTO padding SELECT randomblob(1000000) FROM c;",
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let backup_path = temp_db_path();
+        let backup = {
+            let db = db.clone();
+            let backup_path = backup_path.clone();
+            tokio::spawn(async move { db.backup_to(&backup_path).await })
+        };
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let event = EventBuilder::text_note("During backup", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(db.save_event(&event).await.unwrap());
+        assert!(!backup.is_finished());
+        backup.await.unwrap().unwrap();
+
+        // Snapshot taken before the write
+        let copy = SQLiteDatabase::open(&backup_path).await.unwrap();
+        assert!(copy.is_empty().await.unwrap());
+        drop(copy);
+        remove_db_files(&backup_path);
+
+        // Without read connections
+        drop(db);
+        let opts = SQLiteOptions::new().journal_mode(JournalMode::Delete);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        db.backup_to(&backup_path).await.unwrap();
+        let copy = SQLiteDatabase::open(&backup_path).await.unwrap();
+        assert_eq!(copy.len().await.unwrap(), 1);
+        drop(copy);
+        remove_db_files(&backup_path);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_vacuum_into() {
+        let db = db_with_text_notes(5).await;
+        let path = temp_db_path();
+
+        db.vacuum_into(&path).await.unwrap();
+        let copy = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(copy.len().await.unwrap(), 5);
+        drop(copy);
+
+        // Target already exists
+        assert!(db.vacuum_into(&path).await.is_err());
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_restore_from() {
+        let db = db_with_text_notes(5).await;
+        let path = temp_db_path();
+        db.backup_to(&path).await.unwrap();
+
+        let restored = db_with_text_notes(2).await;
+        restored.restore_from(&path).await.unwrap();
+        assert_eq!(restored.len().await.unwrap(), 5);
+        let expected: BTreeSet<Event> = db
+            .query(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        let events: BTreeSet<Event> = restored
+            .query(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(events, expected);
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_export_jsonl() {
+        // More than a batch
+        let db = db_with_text_notes(BATCH_SIZE as u64 + 5).await;
+
+        let mut buf: Vec<u8> = Vec::new();
+        let exported = db.export_jsonl(&mut buf).await.unwrap();
+        assert_eq!(exported, BATCH_SIZE + 5);
+
+        let jsonl = String::from_utf8(buf).unwrap();
+        assert!(jsonl.ends_with('\n'));
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), exported);
+        for line in lines.into_iter() {
+            let event = Event::from_json(line).unwrap();
+            assert!(db.has_event_already_been_saved(&event.id()).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_jsonl() {
+        let db = db_with_text_notes(5).await;
+        let mut buf: Vec<u8> = Vec::new();
+        db.export_jsonl(&mut buf).await.unwrap();
+        buf.extend_from_slice(b"\nnot an event\n");
+
+        // Strict
+        let imported = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(matches!(
+            imported.import_jsonl(buf.as_slice(), true).await,
+            Err(Error::Event(_))
+        ));
+
+        let imported = SQLiteDatabase::open_in_memory().await.unwrap();
+        let report = imported.import_jsonl(buf.as_slice(), false).await.unwrap();
+        assert_eq!(
+            report,
+            JsonlImport {
+                imported: 5,
+                skipped: 1
+            }
+        );
+        assert_eq!(imported.len().await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_ids() {
+        let db = db_with_text_notes(3).await;
+        let ids: Vec<EventId> = db
+            .event_ids_by_filters(vec![Filter::new()], Order::Desc)
+            .await
+            .unwrap();
+        let unknown = EventBuilder::text_note("Unknown", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        let deleted = db
+            .delete_by_ids(&[ids[0], ids[1], unknown.id()])
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 1);
+        assert!(db.has_event_id_been_deleted(&ids[0]).await.unwrap());
+        assert!(!db.save_event(&unknown).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_expired() {
+        let db = db_with_text_notes(2).await;
+        let keys = Keys::generate();
+        let now = Timestamp::now();
+
+        for expiration in [now + 60_u64, now + 120_u64] {
+            let event = EventBuilder::text_note("Expiring", [Tag::Expiration(expiration)])
+                .to_event(&keys)
+                .unwrap();
+            assert!(db.save_event(&event).await.unwrap());
+        }
+
+        assert_eq!(db.delete_expired(now).await.unwrap(), 0);
+
+        // Expiration equal to `now` is expired
+        assert_eq!(db.delete_expired(now + 60_u64).await.unwrap(), 1);
+        assert_eq!(db.len().await.unwrap(), 3);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 3);
+
+        assert_eq!(db.delete_expired(now + 3600_u64).await.unwrap(), 1);
+        assert_eq!(db.delete_expired(now + 3600_u64).await.unwrap(), 0);
+
+        // Events without expiration are untouched
+        assert_eq!(db.len().await.unwrap(), 2);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete_older_than() {
+        let db = db_with_text_notes(10).await;
+        let keys = Keys::generate();
+        let metadata = EventBuilder::new(Kind::Metadata, "{}", [])
+            .custom_created_at(Timestamp::from(1_600_000_000))
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&metadata).await.unwrap();
+        let url = Url::parse("wss://relay.damus.io").unwrap();
+        db.event_id_seen(metadata.id(), url).await.unwrap();
+
+        // Notes created at 1_700_000_000 + i
+        let deleted = db
+            .delete_older_than(Timestamp::from(1_700_000_004))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 5);
+        assert_eq!(db.len().await.unwrap(), 6);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 6);
+
+        // Replaceable event and seen relays removed too
+        assert!(db
+            .query(vec![Filter::new().kind(Kind::Metadata)], Order::Desc)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!db
+            .has_event_already_been_seen(&metadata.id())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_save_events() {
+        let db = db_with_text_notes(1).await;
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        let old_metadata = EventBuilder::new(Kind::Metadata, "{}", [])
+            .custom_created_at(Timestamp::from(1_600_000_000))
+            .to_event(&keys)
+            .unwrap();
+        let new_metadata = EventBuilder::new(Kind::Metadata, "{}", [])
+            .custom_created_at(Timestamp::from(1_600_000_001))
+            .to_event(&keys)
+            .unwrap();
+
+        let stored = db
+            .save_events(&[
+                note.clone(),
+                new_metadata.clone(),
+                old_metadata,
+                note.clone(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(stored, [true, true, false, false]);
+        assert_eq!(db.len().await.unwrap(), 3);
+
+        let events = db
+            .query(vec![Filter::new().kind(Kind::Metadata)], Order::Desc)
+            .await
+            .unwrap();
+        assert_eq!(events, [new_metadata]);
+    }
+
+    #[tokio::test]
+    async fn test_read_connections() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open_with_opts(&path, SQLiteOptions::new().read_connections(2))
+            .await
+            .unwrap();
+        let keys = Keys::generate();
+
+        // Writes are visible to the read connections as soon as they are completed
+        for i in 0..5 {
+            let event = EventBuilder::text_note(format!("Event #{i}"), [])
+                .to_event(&keys)
+                .unwrap();
+            db.save_event(&event).await.unwrap();
+            assert_eq!(db.event_by_id(event.id()).await.unwrap(), event);
+        }
+
+        let (a, b, c) = tokio::join!(
+            db.query(vec![Filter::new()], Order::Desc),
+            db.query(vec![Filter::new()], Order::Desc),
+            db.count(vec![Filter::new()]),
+        );
+        assert_eq!(a.unwrap().len(), 5);
+        assert_eq!(b.unwrap().len(), 5);
+        assert_eq!(c.unwrap(), 5);
+
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        db.ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_event_outcome() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let metadata = |ts: u64| {
+            EventBuilder::new(Kind::Metadata, "{}", [])
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let old = metadata(1_600_000_000);
+        let new = metadata(1_600_000_001);
+
+        assert_eq!(
+            db.save_event_outcome(&old).await.unwrap(),
+            SaveOutcome::Stored
+        );
+        assert_eq!(
+            db.save_event_outcome(&old).await.unwrap(),
+            SaveOutcome::AlreadyHave
+        );
+        assert_eq!(
+            db.save_event_outcome(&new).await.unwrap(),
+            SaveOutcome::Replaced
+        );
+        assert_eq!(
+            db.save_event_outcome(&old).await.unwrap(),
+            SaveOutcome::Rejected
+        );
+
+        let ephemeral = EventBuilder::new(Kind::Custom(20_000), "", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(
+            db.save_event_outcome(&ephemeral).await.unwrap(),
+            SaveOutcome::Rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_ephemeral() {
+        let keys = Keys::generate();
+        let ephemeral = EventBuilder::new(Kind::Custom(20_001), "", [])
+            .to_event(&keys)
+            .unwrap();
+        let ephemeral2 = EventBuilder::new(Kind::Custom(20_002), "", [])
+            .to_event(&keys)
+            .unwrap();
+
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        assert!(!db.save_event(&ephemeral).await.unwrap());
+        db.bulk_import(BTreeSet::from([ephemeral2.clone()]))
+            .await
+            .unwrap();
+        assert!(db.is_empty().await.unwrap());
+
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().store_ephemeral(true);
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        assert!(db.save_event(&ephemeral).await.unwrap());
+        db.bulk_import(BTreeSet::from([ephemeral2.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(db.len().await.unwrap(), 2);
+        assert_eq!(db.event_by_id(ephemeral.id()).await.unwrap(), ephemeral);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_import_counted() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let metadata = |ts: u64| {
+            EventBuilder::new(Kind::Metadata, "{}", [])
+                .custom_created_at(Timestamp::from(ts))
+                .to_event(&keys)
+                .unwrap()
+        };
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&metadata(1_600_000_000)).await.unwrap();
+        db.save_event(&note).await.unwrap();
+
+        let events: BTreeSet<Event> = [
+            note,
+            metadata(1_600_000_001),
+            metadata(1_600_000_002),
+            EventBuilder::text_note("New", []).to_event(&keys).unwrap(),
+        ]
+        .into_iter()
+        .collect();
+        let result = db.bulk_import_counted(events).await.unwrap();
+        assert_eq!(
+            result,
+            BulkImportResult {
+                stored: 2,
+                discarded: 1,
+                ignored: 2,
+                rejected: 0,
+            }
+        );
+        assert_eq!(db.len().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_import_verified() {
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let keys = Keys::generate();
+        let valid = EventBuilder::text_note("Valid", [])
+            .to_event(&keys)
+            .unwrap();
+        let json = EventBuilder::text_note("Original", [])
+            .to_event(&keys)
+            .unwrap()
+            .as_json()
+            .replace("Original", "Forged");
+        let forged = Event::from_json(json).unwrap();
+
+        let events: BTreeSet<Event> = [valid.clone(), forged.clone()].into_iter().collect();
+        let result = db.bulk_import_verified(events).await.unwrap();
+        assert_eq!(result.stored, 1);
+        assert_eq!(result.rejected, 1);
+        assert!(db.has_event_already_been_saved(&valid.id()).await.unwrap());
+        assert!(!db.has_event_already_been_saved(&forged.id()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_negentropy_items() {
+        let db = db_with_text_notes(10).await;
+
+        let mut expected = db
+            .indexes
+            .negentropy_items(Filter::new().kind(Kind::TextNote))
+            .await;
+        expected.sort_by_key(|(id, created_at)| (*created_at, *id));
+
+        let items = db
+            .negentropy_items(Filter::new().kind(Kind::TextNote))
+            .await
+            .unwrap();
+        assert_eq!(items, expected);
+
+        // Sorted by timestamp, keeping the newest if limited
+        let items = db
+            .negentropy_items(Filter::new().since(Timestamp::from(1_700_000_002)).limit(3))
+            .await
+            .unwrap();
+        let timestamps: Vec<u64> = items.into_iter().map(|(_, t)| t.as_u64()).collect();
+        assert_eq!(timestamps, [1_700_000_007, 1_700_000_008, 1_700_000_009]);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler() {
+        let db = db_with_text_notes(3).await;
+        let metrics: Arc<std::sync::Mutex<Vec<MetricEvent>>> = Arc::default();
+        let m = metrics.clone();
+        db.set_metrics_handler(move |metric| m.lock().unwrap().push(metric));
+
+        db.query(vec![Filter::new()], Order::Desc).await.unwrap();
+        db.count(vec![Filter::new().kind(Kind::Reaction)])
+            .await
+            .unwrap();
+        assert!(db.event_by_id(EventId::all_zeros()).await.is_err());
+
+        db.remove_metrics_handler();
+        db.query(vec![Filter::new()], Order::Desc).await.unwrap();
+
+        let metrics: Vec<(Operation, usize, bool)> = metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| (m.operation, m.result_size, m.success))
+            .collect();
+        assert_eq!(
+            metrics,
+            [
+                (Operation::Query, 3, true),
+                (Operation::Count, 0, true),
+                (Operation::EventById, 0, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_with_migrations() {
+        let path = temp_db_path();
+        let v1 = Migration {
+            version: 1,
+            sql: "CREATE TABLE settings (key TEXT PRIMARY KEY NOT NULL, value TEXT);",
+        };
+        let v2 = Migration {
+            version: 2,
+            sql: "INSERT INTO settings (key, value) VALUES ('theme', 'dark');",
+        };
+
+        let db = SQLiteDatabase::open_with_migrations(&path, &[v1])
+            .await
+            .unwrap();
+        assert_eq!(
+            db.schema_version().await.unwrap(),
+            migration::DB_VERSION as u32
+        );
+        drop(db);
+
+        // Already applied migrations are skipped
+        let db = SQLiteDatabase::open_with_migrations(&path, &[v1, v2])
+            .await
+            .unwrap();
+        let value: String = db
+            .pool
+            .interact(|conn| {
+                conn.query_row(
+                    "SELECT value FROM settings WHERE key = 'theme';",
+                    [],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "dark");
+        drop(db);
+
+        // Custom version newer than the latest migration
+        assert!(matches!(
+            SQLiteDatabase::open_with_migrations(&path, &[v1]).await,
+            Err(Error::Migration(MigrationError::NewerCustomVersion {
+                current: 2,
+                latest: 1
+            }))
+        ));
+
+        // Not strictly increasing
+        assert!(matches!(
+            SQLiteDatabase::open_with_migrations(&path, &[v2, v1]).await,
+            Err(Error::Migration(MigrationError::InvalidCustomMigration {
+                version: 1
+            }))
+        ));
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_with_read_connection() {
+        let db = db_with_text_notes(3).await;
+        let count: usize = db
+            .with_read_connection(|conn| {
+                conn.query_row(
+                    "SELECT COUNT(*) FROM events WHERE kind = ?;",
+                    [Kind::TextNote.as_u32()],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_event() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&event).await.unwrap();
+        db.pool
+            .interact(|conn| {
+                conn.execute(
+                    "INSERT INTO events (event_id, event) VALUES (?, ?);",
+                    (EventId::all_zeros().to_hex(), b"not a flatbuffer".to_vec()),
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        drop(db);
+
+        // Fail-fast
+        let opts = SQLiteOptions::new().strict_decode(true);
+        assert!(SQLiteDatabase::open_with_opts(&path, opts).await.is_err());
+
+        // Skipped
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(
+            db.query(vec![Filter::new()], Order::Desc).await.unwrap(),
+            [event.clone()]
+        );
+        assert_eq!(db.len().await.unwrap(), 2);
+        drop(db);
+
+        // Skipped and deleted
+        let opts = SQLiteOptions::new().delete_corrupt(true);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        assert_eq!(
+            db.query(vec![Filter::new()], Order::Desc).await.unwrap(),
+            [event]
+        );
+        assert_eq!(db.len().await.unwrap(), 1);
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_migration_with_corrupt_event() {
+        let path = temp_db_path();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+
+        // Version 1 database, with a valid and a corrupt event
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(include_str!("../migrations/001_init.sql"))
+            .unwrap();
+        let mut fbb = FlatBufferBuilder::with_capacity(70_000);
+        let value: Vec<u8> = encode_event(&mut fbb, &event, 70_000, StorageFormat::FlatBuffer);
+        conn.execute(
+            "INSERT INTO events (event_id, event) VALUES (?, ?);",
+            (event.id.to_hex(), value),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO events (event_id, event) VALUES (?, ?);",
+            (EventId::all_zeros().to_hex(), b"not a flatbuffer".to_vec()),
+        )
+        .unwrap();
+        drop(conn);
+
+        // Fail-fast: the migration is rolled back
+        let opts = SQLiteOptions::new().strict_decode(true);
+        assert!(SQLiteDatabase::open_with_opts(&path, opts).await.is_err());
+
+        // Skipped
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(
+            db.schema_version().await.unwrap(),
+            migration::DB_VERSION as u32
+        );
+        let filter = Filter::new().author(keys.public_key());
+        assert_eq!(db.query(vec![filter], Order::Desc).await.unwrap(), [event]);
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let db = db_with_text_notes(3).await;
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        let url = Url::parse("wss://relay.damus.io").unwrap();
+
+        db.transaction(|tx| {
+            tx.save_event(note.clone());
+            tx.event_id_seen(note.id(), url.clone());
+            tx.delete(Filter::new().kind(Kind::Reaction));
+        })
+        .await
+        .unwrap();
+        assert_eq!(db.len().await.unwrap(), 4);
+        assert!(db.has_event_already_been_saved(&note.id()).await.unwrap());
+        assert_eq!(
+            db.event_seen_on_relays(note.id()).await.unwrap(),
+            Some(HashSet::from([url]))
+        );
+
+        db.transaction(|tx| tx.delete(Filter::new().author(keys.public_key())))
+            .await
+            .unwrap();
+        assert_eq!(db.len().await.unwrap(), 3);
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_storage_format() {
+        let keys = Keys::generate();
+        let flatbuffer = EventBuilder::text_note("FlatBuffers", [])
+            .to_event(&keys)
+            .unwrap();
+        let json = EventBuilder::text_note("JSON", [Tag::parse(&["unknown", "a", "b"]).unwrap()])
+            .custom_created_at(flatbuffer.created_at() + 1_u64)
+            .to_event(&keys)
+            .unwrap();
+
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.save_event(&flatbuffer).await.unwrap();
+        drop(db);
+
+        let opts = SQLiteOptions::new().storage_format(StorageFormat::Json);
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        db.save_event(&json).await.unwrap();
+        assert_eq!(db.event_by_id(json.id()).await.unwrap(), json);
+        drop(db);
+
+        // Both formats are read, the indexes are built from both
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        let formats: Vec<u8> = db
+            .pool
+            .interact(|conn| {
+                let mut stmt = conn.prepare("SELECT format FROM events ORDER BY rowid;")?;
+                let formats = stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<Vec<u8>, _>>()?;
+                Ok::<_, Error>(formats)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(formats, vec![format::FLATBUFFER, format::JSON]);
+        assert_eq!(
+            db.query(vec![Filter::new().author(keys.public_key())], Order::Desc)
+                .await
+                .unwrap(),
+            vec![json, flatbuffer]
+        );
+        drop(db);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_reencode_events() {
+        let db = db_with_text_notes(3).await;
+        let expected = db.query(vec![Filter::new()], Order::Desc).await.unwrap();
+        assert_eq!(db.reencode_events().await.unwrap(), 0);
+
+        // Switch to JSON
+        let mut json = db.clone();
+        json.opts = SQLiteOptions::new().storage_format(StorageFormat::Json);
+        assert_eq!(json.reencode_events().await.unwrap(), 3);
+        assert_eq!(json.reencode_events().await.unwrap(), 0);
+        let formats: Vec<u8> = db
+            .pool
+            .interact(|conn| {
+                let mut stmt = conn.prepare("SELECT DISTINCT format FROM events;")?;
+                let formats = stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<Vec<u8>, _>>()?;
+                Ok::<_, Error>(formats)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(formats, vec![format::JSON]);
+        assert_eq!(
+            db.query(vec![Filter::new()], Order::Desc).await.unwrap(),
+            expected
+        );
+
+        // And back
+        assert_eq!(db.reencode_events().await.unwrap(), 3);
+        assert_eq!(
+            db.query(vec![Filter::new()], Order::Desc).await.unwrap(),
+            expected
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_compression() {
+        let path = temp_db_path();
+        let keys = Keys::generate();
+        let plain = EventBuilder::text_note("Plain", [])
+            .to_event(&keys)
+            .unwrap();
+        let compressed = EventBuilder::text_note("Compressed ".repeat(100), [])
+            .to_event(&keys)
+            .unwrap();
+
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.save_event(&plain).await.unwrap();
+        drop(db);
+
+        let opts = SQLiteOptions::new().compression(Some(3));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        db.save_event(&compressed).await.unwrap();
+        let flags: Vec<u8> = db
+            .with_read_connection(|conn| {
+                let mut stmt = conn.prepare("SELECT compression FROM events ORDER BY rowid;")?;
+                let flags = stmt.query_map([], |row| row.get(0))?;
+                flags.collect()
+            })
+            .await
+            .unwrap();
+        assert_eq!(flags, [0, 1]);
+        drop(db);
+
+        // Both rows are decoded when building the indexes, and on read
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        assert_eq!(db.count(vec![Filter::new()]).await.unwrap(), 2);
+        assert_eq!(db.event_by_id(compressed.id()).await.unwrap(), compressed);
+        assert_eq!(db.event_by_id(plain.id()).await.unwrap(), plain);
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        // Not in WAL mode
+        let db = db_with_text_notes(3).await;
+        db.checkpoint().await.unwrap();
+
+        let path = temp_db_path();
+        let opts = SQLiteOptions::new().import_checkpoint_threshold(Some(0));
+        let db = SQLiteDatabase::open_with_opts(&path, opts).await.unwrap();
+        let keys = Keys::generate();
+        let events: BTreeSet<Event> = (0..10)
+            .map(|i| {
+                EventBuilder::text_note(format!("Event #{i}"), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+        db.bulk_import(events).await.unwrap();
+
+        let mut wal = path.as_os_str().to_owned();
+        wal.push("-wal");
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        db.save_event(
+            &EventBuilder::text_note("Hello", [])
+                .to_event(&keys)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        db.checkpoint().await.unwrap();
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_flatbuffer_capacity() {
+        let conn = Connection::open_in_memory().unwrap();
+        let opts = SQLiteOptions::new().flatbuffer_capacity(1024);
+        let db = SQLiteDatabase::new(None, conn, Vec::new(), opts, &[], None)
+            .await
+            .unwrap();
+        let keys = Keys::generate();
+        let large = EventBuilder::text_note("a".repeat(10_000), [])
+            .to_event(&keys)
+            .unwrap();
+        let small = EventBuilder::text_note("b", []).to_event(&keys).unwrap();
+
+        db.save_event(&large).await.unwrap();
+        db.save_event(&small).await.unwrap();
+
+        assert_eq!(db.event_by_id(large.id()).await.unwrap(), large);
+        assert_eq!(db.event_by_id(small.id()).await.unwrap(), small);
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_schema() {
+        let path = temp_db_path();
+        let db = SQLiteDatabase::open(&path).await.unwrap();
+        db.pool
+            .interact(|conn| conn.pragma_update(None, "user_version", 1000))
+            .await
+            .unwrap()
+            .unwrap();
+        drop(db);
+
+        match SQLiteDatabase::open(&path).await {
+            Err(Error::IncompatibleSchema { found, supported }) => {
+                assert_eq!(found, 1000);
+                assert_eq!(supported, migration::DB_VERSION as u32);
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        async fn next(subscription: &mut Subscription) -> Option<Event> {
+            std::future::poll_fn(|cx| {
+                futures_core::Stream::poll_next(std::pin::Pin::new(&mut *subscription), cx)
+            })
+            .await
+        }
+
+        let db = SQLiteDatabase::open_in_memory().await.unwrap();
+        let mut reactions = db.subscribe(Filter::new().kind(Kind::Reaction));
+        let mut notes = db.subscribe(Filter::new().kind(Kind::TextNote));
+
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("Hello", [])
+            .to_event(&keys)
+            .unwrap();
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        db.save_event(&note).await.unwrap();
+        // The note is already stored: not notified again
+        db.save_events(&[reaction.clone(), note.clone()])
+            .await
+            .unwrap();
+        drop(db);
+
+        assert_eq!(next(&mut reactions).await, Some(reaction));
+        assert_eq!(next(&mut reactions).await, None);
+        assert_eq!(next(&mut notes).await, Some(note));
+        assert_eq!(next(&mut notes).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_integrity_check() {
+        let db = db_with_text_notes(3).await;
+        assert_eq!(db.integrity_check().await.unwrap(), ["ok"]);
+        assert_eq!(db.quick_check().await.unwrap(), ["ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_query_paginated() {
+        let db = db_with_text_notes(10).await;
+
+        let page = db
+            .query_paginated(vec![Filter::new()], Order::Desc, 4, 0)
+            .await
+            .unwrap();
+        let contents: Vec<&str> = page.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, ["Event #9", "Event #8", "Event #7", "Event #6"]);
+
+        // Last page is partial
+        let page = db
+            .query_paginated(vec![Filter::new()], Order::Desc, 4, 8)
+            .await
+            .unwrap();
+        let contents: Vec<&str> = page.iter().map(|e| e.content()).collect();
+        assert_eq!(contents, ["Event #1", "Event #0"]);
+
+        // Offset past the end
+        assert!(db
+            .query_paginated(vec![Filter::new()], Order::Desc, 4, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Zero limit
+        assert!(db
+            .query_paginated(vec![Filter::new()], Order::Asc, 0, 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[cfg(bench)]
+mod benches {
+    use nostr::{EventBuilder, Keys, Tag};
+    use test::Bencher;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    const TASKS: usize = 10;
+    const EVENTS_PER_TASK: usize = 10;
+    const DELETED_EVENTS: usize = 10_000;
+    #[cfg(feature = "zstd")]
+    const COMPRESSED_EVENTS: usize = 1000;
+
+    #[bench]
+    pub fn save_event_concurrent(bh: &mut Bencher) {
+        let rt = Runtime::new().unwrap();
+        let keys = Keys::generate();
+        let events: Vec<Vec<Event>> = (0..TASKS)
+            .map(|t| {
+                (0..EVENTS_PER_TASK)
+                    .map(|i| {
+                        EventBuilder::text_note(format!("Task #{t}, event #{i}"), [])
+                            .to_event(&keys)
+                            .unwrap()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        bh.iter(|| {
+            rt.block_on(async {
+                let db = SQLiteDatabase::open_in_memory().await.unwrap();
+                let handles: Vec<_> = events
+                    .iter()
+                    .cloned()
+                    .map(|events| {
+                        let db = db.clone();
+                        tokio::spawn(async move {
+                            for event in events.iter() {
+                                db.save_event(event).await.unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles.into_iter() {
+                    handle.await.unwrap();
+                }
+            });
+        });
+    }
+
+    #[bench]
+    pub fn delete_large(bh: &mut Bencher) {
+        let rt = Runtime::new().unwrap();
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..DELETED_EVENTS)
+            .map(|i| {
+                EventBuilder::text_note(format!("Event #{i}"), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .collect();
+
+        bh.iter(|| {
+            rt.block_on(async {
+                let db = SQLiteDatabase::open_in_memory().await.unwrap();
+                db.save_events(&events).await.unwrap();
+                db.delete(Filter::new().author(keys.public_key()))
+                    .await
+                    .unwrap();
+            });
+        });
+    }
+
+    #[cfg(feature = "zstd")]
+    #[bench]
+    pub fn save_events_zstd(bh: &mut Bencher) {
+        let rt = Runtime::new().unwrap();
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..COMPRESSED_EVENTS)
+            .map(|i| {
+                EventBuilder::long_form_text_note(
+                    format!(
+                        "Article #{i}: {}",
+                        "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(50)
+                    ),
+                    [Tag::Identifier(format!("article-{i}"))],
+                )
+                .to_event(&keys)
+                .unwrap()
+            })
+            .collect();
+
+        for level in [None, Some(3)] {
+            let size: u64 = rt.block_on(async {
+                let opts = SQLiteOptions::new().compression(level);
+                let db = SQLiteDatabase::new(
+                    None,
+                    Connection::open_in_memory().unwrap(),
+                    Vec::new(),
+                    opts,
+                    &[],
+                    None,
+                )
+                .await
+                .unwrap();
+                db.save_events(&events).await.unwrap();
+                db.database_size().await.unwrap()
+            });
+            println!("Compression {level:?}: {size} bytes for {COMPRESSED_EVENTS} events");
+        }
+
+        bh.iter(|| {
+            rt.block_on(async {
+                let opts = SQLiteOptions::new().compression(Some(3));
+                let db = SQLiteDatabase::new(
+                    None,
+                    Connection::open_in_memory().unwrap(),
+                    Vec::new(),
+                    opts,
+                    &[],
+                    None,
+                )
+                .await
+                .unwrap();
+                db.save_events(&events).await.unwrap();
+            });
+        });
     }
 }
@@ -0,0 +1,148 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Metrics
+
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+type Handler = Arc<dyn Fn(MetricEvent) + Send + Sync>;
+
+/// Database operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// [`NostrDatabase::save_event`](nostr_database::NostrDatabase::save_event)
+    SaveEvent,
+    /// [`NostrDatabase::bulk_import`](nostr_database::NostrDatabase::bulk_import)
+    BulkImport,
+    /// [`NostrDatabase::has_event_already_been_saved`](nostr_database::NostrDatabase::has_event_already_been_saved)
+    HasEventAlreadyBeenSaved,
+    /// [`NostrDatabase::has_event_already_been_seen`](nostr_database::NostrDatabase::has_event_already_been_seen)
+    HasEventAlreadyBeenSeen,
+    /// [`NostrDatabase::has_event_id_been_deleted`](nostr_database::NostrDatabase::has_event_id_been_deleted)
+    HasEventIdBeenDeleted,
+    /// [`NostrDatabase::has_coordinate_been_deleted`](nostr_database::NostrDatabase::has_coordinate_been_deleted)
+    HasCoordinateBeenDeleted,
+    /// [`NostrDatabase::event_id_seen`](nostr_database::NostrDatabase::event_id_seen)
+    EventIdSeen,
+    /// [`NostrDatabase::event_seen_on_relays`](nostr_database::NostrDatabase::event_seen_on_relays)
+    EventSeenOnRelays,
+    /// [`NostrDatabase::event_by_id`](nostr_database::NostrDatabase::event_by_id)
+    EventById,
+    /// [`NostrDatabase::count`](nostr_database::NostrDatabase::count)
+    Count,
+    /// [`NostrDatabase::query`](nostr_database::NostrDatabase::query)
+    Query,
+    /// [`NostrDatabase::event_ids_by_filters`](nostr_database::NostrDatabase::event_ids_by_filters)
+    EventIdsByFilters,
+    /// [`NostrDatabase::negentropy_items`](nostr_database::NostrDatabase::negentropy_items)
+    NegentropyItems,
+    /// [`NostrDatabase::delete`](nostr_database::NostrDatabase::delete)
+    Delete,
+    /// [`NostrDatabase::wipe`](nostr_database::NostrDatabase::wipe)
+    Wipe,
+}
+
+impl Operation {
+    /// Name of the operation (i.e. `save_event`)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SaveEvent => "save_event",
+            Self::BulkImport => "bulk_import",
+            Self::HasEventAlreadyBeenSaved => "has_event_already_been_saved",
+            Self::HasEventAlreadyBeenSeen => "has_event_already_been_seen",
+            Self::HasEventIdBeenDeleted => "has_event_id_been_deleted",
+            Self::HasCoordinateBeenDeleted => "has_coordinate_been_deleted",
+            Self::EventIdSeen => "event_id_seen",
+            Self::EventSeenOnRelays => "event_seen_on_relays",
+            Self::EventById => "event_by_id",
+            Self::Count => "count",
+            Self::Query => "query",
+            Self::EventIdsByFilters => "event_ids_by_filters",
+            Self::NegentropyItems => "negentropy_items",
+            Self::Delete => "delete",
+            Self::Wipe => "wipe",
+        }
+    }
+}
+
+/// Metric of a completed database operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricEvent {
+    /// Operation
+    pub operation: Operation,
+    /// Time taken by the operation
+    pub duration: Duration,
+    /// Number of returned or counted items (i.e. events, IDs or relays), `1` if a single event
+    /// was found, saved or marked as seen, number of submitted events for bulk imports.
+    /// Always `0` if the operation failed.
+    pub result_size: usize,
+    /// If the operation completed successfully
+    pub success: bool,
+}
+
+/// Metrics handler, shared between the clones of the database
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    handler: Arc<RwLock<Option<Handler>>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("handler", &self.handler().is_some())
+            .finish()
+    }
+}
+
+impl Metrics {
+    pub fn set_handler(&self, handler: Option<Handler>) {
+        let mut h = match self.handler.write() {
+            Ok(h) => h,
+            Err(e) => e.into_inner(),
+        };
+        *h = handler;
+    }
+
+    fn handler(&self) -> Option<Handler> {
+        match self.handler.read() {
+            Ok(h) => h.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Run the operation, reporting its metric to the handler (if set)
+    ///
+    /// The Sqlite errors are wrapped in [`Error::Operation`], with the name of the operation.
+    pub async fn measure<T, Fut, S>(
+        &self,
+        operation: Operation,
+        fut: Fut,
+        size: S,
+    ) -> Result<T, Error>
+    where
+        Fut: Future<Output = Result<T, Error>>,
+        S: FnOnce(&T) -> usize,
+    {
+        let res = match self.handler() {
+            Some(handler) => {
+                let start = Instant::now();
+                let res = fut.await;
+                handler(MetricEvent {
+                    operation,
+                    duration: start.elapsed(),
+                    result_size: res.as_ref().map(size).unwrap_or(0),
+                    success: res.is_ok(),
+                });
+                res
+            }
+            None => fut.await,
+        };
+        res.map_err(|e| e.context(operation.as_str()))
+    }
+}
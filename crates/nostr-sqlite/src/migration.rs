@@ -4,31 +4,115 @@
 
 use std::cmp::Ordering;
 
-use deadpool_sqlite::Object;
+use nostr::Event;
 use rusqlite::Connection;
 use thiserror::Error;
 
-use super::Error;
+use super::{decode_event, insert_coordinate, insert_tags, Error};
 
 /// Latest database version
-pub const DB_VERSION: usize = 1;
+pub const DB_VERSION: usize = 13;
+
+/// Number of events decoded at time when filling new columns
+const BACKFILL_BATCH_SIZE: usize = 1000;
 
 /// Startup DB Pragmas
 pub const STARTUP_SQL: &str = r##"
-PRAGMA main.synchronous=NORMAL;
 PRAGMA foreign_keys = ON;
 PRAGMA journal_size_limit=32768;
-pragma mmap_size = 17179869184; -- cap mmap at 16GB
+CREATE INDEX IF NOT EXISTS events_author_index ON events(author);
 "##;
 
+/// Tables created by the built-in migrations
+const TABLES: &[&str] = &[
+    "events",
+    "event_seen_by_relays",
+    "event_tags",
+    "event_coordinates",
+    "deleted_events",
+];
+
+/// Indexes created by the built-in migrations, with the statement to recreate them
+const INDEXES: &[(&str, &str)] = &[
+    (
+        "event_seen_by_relays_index",
+        "CREATE UNIQUE INDEX IF NOT EXISTS event_seen_by_relays_index ON event_seen_by_relays(event_id,relay_url);",
+    ),
+    (
+        "events_kind_index",
+        "CREATE INDEX IF NOT EXISTS events_kind_index ON events(kind);",
+    ),
+    (
+        "events_expiration_index",
+        "CREATE INDEX IF NOT EXISTS events_expiration_index ON events(expiration) WHERE expiration IS NOT NULL;",
+    ),
+    (
+        "events_created_at_index",
+        "CREATE INDEX IF NOT EXISTS events_created_at_index ON events(created_at);",
+    ),
+    (
+        "event_tags_index",
+        "CREATE UNIQUE INDEX IF NOT EXISTS event_tags_index ON event_tags(tag_name,tag_value,event_id);",
+    ),
+    (
+        "event_tags_event_id_index",
+        "CREATE INDEX IF NOT EXISTS event_tags_event_id_index ON event_tags(event_id);",
+    ),
+    (
+        "event_coordinates_event_id_index",
+        "CREATE INDEX IF NOT EXISTS event_coordinates_event_id_index ON event_coordinates(event_id);",
+    ),
+    (
+        "event_seen_by_relays_seen_at_index",
+        "CREATE INDEX IF NOT EXISTS event_seen_by_relays_seen_at_index ON event_seen_by_relays(seen_at);",
+    ),
+    (
+        "events_author_index",
+        "CREATE INDEX IF NOT EXISTS events_author_index ON events(author);",
+    ),
+    (
+        "events_content_len_index",
+        "CREATE INDEX IF NOT EXISTS events_content_len_index ON events(content_len);",
+    ),
+];
+
+/// Table tracking the applied custom [`Migration`]s
+///
+/// Kept apart from `user_version`, so that new built-in migrations don't collide with them.
+const CUSTOM_MIGRATIONS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS custom_migrations (version INTEGER PRIMARY KEY NOT NULL);";
+
+/// Application-specific migration, applied after the built-in ones
+///
+/// Like the built-in migrations, each one is applied once, in a transaction, when the stored
+/// custom version is lower than [`Migration::version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Migration {
+    /// Schema version reached once applied (must be greater than `0`)
+    ///
+    /// The versions of the migrations passed to
+    /// [`SQLiteDatabase::open_with_migrations`](crate::SQLiteDatabase::open_with_migrations)
+    /// must be strictly increasing.
+    pub version: u32,
+    /// SQL statements to execute
+    pub sql: &'static str,
+}
+
 /// Schema error
 #[derive(Debug, Error)]
 pub enum MigrationError {
-    /// Database versione newer than supported
-    #[error(
-        "Database version is newer than supported by this executable (v{current} > v{DB_VERSION})"
-    )]
-    NewerDbVersion { current: usize },
+    /// Database version older than supported and can't be upgraded (i.e. read-only mode)
+    #[error("Database version is older than supported by this executable and can't be upgraded (v{current} < v{DB_VERSION})")]
+    OlderDbVersion { current: usize },
+    /// Custom migration versions not greater than `0` or not strictly increasing
+    #[error("Invalid custom migration version: v{version}")]
+    InvalidCustomMigration { version: u32 },
+    /// Custom database version newer than the latest custom migration
+    #[error("Custom database version is newer than the latest custom migration (v{current} > v{latest})")]
+    NewerCustomVersion { current: u32, latest: u32 },
+    /// Custom database version older than the latest custom migration and can't be upgraded (i.e. read-only mode)
+    #[error("Custom database version is older than the latest custom migration and can't be upgraded (v{current} < v{latest})")]
+    OlderCustomVersion { current: u32, latest: u32 },
 }
 
 /// Determine the current application database schema version.
@@ -39,68 +123,238 @@ pub fn curr_db_version(conn: &mut Connection) -> Result<usize, Error> {
 }
 
 /// Upgrade DB to latest version, and execute pragma settings
-pub(crate) async fn run(conn: &Object) -> Result<(), Error> {
-    conn.interact(|conn| {
-        // check the version.
-        let mut curr_version = curr_db_version(conn)?;
-        tracing::info!("DB version = {:?}", curr_version);
-
-        match curr_version.cmp(&DB_VERSION) {
-            // Database is new or not current
-            Ordering::Less => {
-                // initialize from scratch
-                if curr_version == 0 {
-                    curr_version = mig_init(conn)?;
-                }
-
-                // for initialized but out-of-date schemas, proceed to
-                // upgrade sequentially until we are current.
-                // if curr_version == 1 {
-                // curr_version = mig_1_to_2(conn)?;
-                // }
-                //
-                // if curr_version == 2 {
-                // curr_version = mig_2_to_3(conn)?;
-                // }
-                //
-                // if curr_version == 3 {
-                // curr_version = mig_3_to_4(conn)?;
-                // }
-                //
-                // if curr_version == 4 {
-                // curr_version = mig_4_to_5(conn)?;
-                // }
-                //
-                // if curr_version == 5 {
-                // curr_version = mig_5_to_6(conn)?;
-                // }
-                //
-                // if curr_version == 6 {
-                // curr_version = mig_6_to_7(conn)?;
-                // }
-
-                if curr_version == DB_VERSION {
-                    tracing::info!("All migration scripts completed successfully (v{DB_VERSION})");
-                }
-            }
-            // Database is current, all is good
-            Ordering::Equal => {
-                tracing::debug!("Database version was already current (v{DB_VERSION})");
-            }
-            // Database is newer than what this code understands, abort
-            Ordering::Greater => {
-                return Err(Error::Migration(MigrationError::NewerDbVersion {
-                    current: curr_version,
-                }));
+pub(crate) fn run(conn: &mut Connection, strict: bool) -> Result<(), Error> {
+    // check the version.
+    let mut curr_version = curr_db_version(conn)?;
+    tracing::info!("DB version = {:?}", curr_version);
+
+    match curr_version.cmp(&DB_VERSION) {
+        // Database is new or not current
+        Ordering::Less => {
+            // initialize from scratch
+            if curr_version == 0 {
+                curr_version = mig_init(conn)?;
+            }
+
+            // for initialized but out-of-date schemas, proceed to
+            // upgrade sequentially until we are current.
+            if curr_version == 1 {
+                curr_version = mig_1_to_2(conn, strict)?;
+            }
+
+            if curr_version == 2 {
+                curr_version = mig_2_to_3(conn, strict)?;
+            }
+
+            if curr_version == 3 {
+                curr_version = mig_3_to_4(conn, strict)?;
+            }
+
+            if curr_version == 4 {
+                curr_version = mig_4_to_5(conn, strict)?;
+            }
+
+            if curr_version == 5 {
+                curr_version = mig_5_to_6(conn)?;
+            }
+
+            if curr_version == 6 {
+                curr_version = mig_6_to_7(conn, strict)?;
+            }
+
+            if curr_version == 7 {
+                curr_version = mig_7_to_8(conn, strict)?;
+            }
+
+            if curr_version == 8 {
+                curr_version = mig_8_to_9(conn)?;
+            }
+
+            if curr_version == 9 {
+                curr_version = mig_9_to_10(conn)?;
+            }
+
+            if curr_version == 10 {
+                curr_version = mig_10_to_11(conn)?;
             }
+
+            if curr_version == 11 {
+                curr_version = mig_11_to_12(conn)?;
+            }
+
+            if curr_version == 12 {
+                curr_version = mig_12_to_13(conn, strict)?;
+            }
+
+            // if curr_version == 13 {
+            // curr_version = mig_13_to_14(conn)?;
+            // }
+
+            if curr_version == DB_VERSION {
+                tracing::info!("All migration scripts completed successfully (v{DB_VERSION})");
+            }
+        }
+        // Database is current, all is good
+        Ordering::Equal => {
+            tracing::debug!("Database version was already current (v{DB_VERSION})");
+        }
+        // Database is newer than what this code understands, abort
+        Ordering::Greater => {
+            return Err(Error::IncompatibleSchema {
+                found: curr_version as u32,
+                supported: DB_VERSION as u32,
+            });
+        }
+    }
+
+    // Setup PRAGMA
+    conn.execute_batch(STARTUP_SQL)?;
+    tracing::debug!("SQLite PRAGMA startup completed");
+
+    repair_indexes(conn)
+}
+
+/// Check the schema, returning the missing tables and indexes (i.e. dropped by hand)
+pub(crate) fn verify(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = ? AND name = ?);",
+    )?;
+    let mut exists = |kind: &str, name: &str| -> Result<bool, Error> {
+        Ok(stmt.query_row((kind, name), |row| row.get(0))?)
+    };
+
+    let mut discrepancies: Vec<String> = Vec::new();
+    for table in TABLES.iter() {
+        if !exists("table", table)? {
+            discrepancies.push(format!("missing table: {table}"));
+        }
+    }
+    for (index, _) in INDEXES.iter() {
+        if !exists("index", index)? {
+            discrepancies.push(format!("missing index: {index}"));
+        }
+    }
+    Ok(discrepancies)
+}
+
+/// Recreate the missing indexes
+fn repair_indexes(conn: &Connection) -> Result<(), Error> {
+    for (index, sql) in INDEXES.iter() {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?);",
+            [index],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            tracing::warn!("Index {index} missing: recreating it");
+            conn.execute_batch(sql)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that DB is at the latest version, without upgrading it
+pub(crate) fn check(conn: &mut Connection) -> Result<(), Error> {
+    let curr_version = curr_db_version(conn)?;
+    tracing::info!("DB version = {:?}", curr_version);
+
+    match curr_version.cmp(&DB_VERSION) {
+        Ordering::Less => Err(Error::Migration(MigrationError::OlderDbVersion {
+            current: curr_version,
+        })),
+        Ordering::Equal => Ok(()),
+        Ordering::Greater => Err(Error::IncompatibleSchema {
+            found: curr_version as u32,
+            supported: DB_VERSION as u32,
+        }),
+    }
+}
+
+/// Apply the custom migrations not applied yet, after the built-in ones
+pub(crate) fn run_custom(conn: &mut Connection, migrations: &[Migration]) -> Result<(), Error> {
+    let latest: u32 = match latest_custom_version(migrations)? {
+        Some(latest) => latest,
+        None => return Ok(()),
+    };
+
+    conn.execute_batch(CUSTOM_MIGRATIONS_SQL)?;
+    let current: u32 = curr_custom_version(conn)?;
+    tracing::info!("Custom DB version = {current}");
+
+    if current > latest {
+        return Err(Error::Migration(MigrationError::NewerCustomVersion {
+            current,
+            latest,
+        }));
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO custom_migrations (version) VALUES (?);",
+            [migration.version],
+        )?;
+        tx.commit()?;
+        tracing::info!("custom database schema upgraded to v{}", migration.version);
+    }
+
+    Ok(())
+}
+
+/// Check that the custom migrations have already been applied, without applying them
+pub(crate) fn check_custom(conn: &mut Connection, migrations: &[Migration]) -> Result<(), Error> {
+    let latest: u32 = match latest_custom_version(migrations)? {
+        Some(latest) => latest,
+        None => return Ok(()),
+    };
+
+    let current: u32 = curr_custom_version(conn)?;
+    tracing::info!("Custom DB version = {current}");
+
+    match current.cmp(&latest) {
+        Ordering::Less => Err(Error::Migration(MigrationError::OlderCustomVersion {
+            current,
+            latest,
+        })),
+        Ordering::Equal => Ok(()),
+        Ordering::Greater => Err(Error::Migration(MigrationError::NewerCustomVersion {
+            current,
+            latest,
+        })),
+    }
+}
+
+/// Latest custom version (if any), checking that versions are strictly increasing
+fn latest_custom_version(migrations: &[Migration]) -> Result<Option<u32>, Error> {
+    let mut latest: Option<u32> = None;
+    for migration in migrations.iter() {
+        if migration.version <= latest.unwrap_or(0) {
+            return Err(Error::Migration(MigrationError::InvalidCustomMigration {
+                version: migration.version,
+            }));
         }
+        latest = Some(migration.version);
+    }
+    Ok(latest)
+}
 
-        // Setup PRAGMA
-        conn.execute_batch(STARTUP_SQL)?;
-        tracing::debug!("SQLite PRAGMA startup completed");
-        Ok(())
-    })
-    .await?
+/// Latest applied custom version (`0` if none)
+fn curr_custom_version(conn: &Connection) -> Result<u32, Error> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'custom_migrations');",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    let version: u32 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM custom_migrations;",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(version)
 }
 
 fn mig_init(conn: &mut Connection) -> Result<usize, Error> {
@@ -109,8 +363,264 @@ fn mig_init(conn: &mut Connection) -> Result<usize, Error> {
     Ok(1)
 }
 
-// fn mig_1_to_2(conn: &mut Connection) -> Result<usize, Error> {
-// conn.execute_batch(include_str!("../../migrations/002_notifications.sql"))?;
-// tracing::info!("database schema upgraded v1 -> v2");
-// Ok(2)
-// }
+fn mig_1_to_2(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/002_kind.sql"))?;
+    {
+        let mut stmt = tx.prepare("UPDATE events SET kind = ? WHERE rowid = ?;")?;
+        backfill(&tx, strict, |rowid, event| {
+            stmt.execute((event.kind().as_u32(), rowid))?;
+            Ok(())
+        })?;
+    }
+    tx.pragma_update(None, "user_version", 2)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v1 -> v2");
+    Ok(2)
+}
+
+fn mig_2_to_3(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/003_author.sql"))?;
+    {
+        let mut stmt = tx.prepare("UPDATE events SET author = ? WHERE rowid = ?;")?;
+        backfill(&tx, strict, |rowid, event| {
+            stmt.execute((event.author().to_hex(), rowid))?;
+            Ok(())
+        })?;
+    }
+    tx.pragma_update(None, "user_version", 3)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v2 -> v3");
+    Ok(3)
+}
+
+fn mig_3_to_4(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/004_expiration.sql"))?;
+    {
+        let mut stmt = tx.prepare("UPDATE events SET expiration = ? WHERE rowid = ?;")?;
+        backfill(&tx, strict, |rowid, event| {
+            if let Some(expiration) = event.expiration() {
+                stmt.execute((expiration.as_u64(), rowid))?;
+            }
+            Ok(())
+        })?;
+    }
+    tx.pragma_update(None, "user_version", 4)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v3 -> v4");
+    Ok(4)
+}
+
+fn mig_4_to_5(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/005_created_at.sql"))?;
+    {
+        let mut stmt = tx.prepare("UPDATE events SET created_at = ? WHERE rowid = ?;")?;
+        backfill(&tx, strict, |rowid, event| {
+            stmt.execute((event.created_at().as_u64(), rowid))?;
+            Ok(())
+        })?;
+    }
+    tx.pragma_update(None, "user_version", 5)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v4 -> v5");
+    Ok(5)
+}
+
+fn mig_5_to_6(conn: &mut Connection) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/006_compression.sql"))?;
+    tx.pragma_update(None, "user_version", 6)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v5 -> v6");
+    Ok(6)
+}
+
+fn mig_6_to_7(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/007_event_tags.sql"))?;
+    backfill(&tx, strict, |_, event| {
+        insert_tags(&tx, &event.id().to_hex(), &event)
+    })?;
+    tx.pragma_update(None, "user_version", 7)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v6 -> v7");
+    Ok(7)
+}
+
+fn mig_7_to_8(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/008_coordinates.sql"))?;
+    backfill(&tx, strict, |_, event| insert_coordinate(&tx, &event))?;
+    tx.pragma_update(None, "user_version", 8)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v7 -> v8");
+    Ok(8)
+}
+
+fn mig_8_to_9(conn: &mut Connection) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/009_deleted_events.sql"))?;
+    tx.pragma_update(None, "user_version", 9)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v8 -> v9");
+    Ok(9)
+}
+
+fn mig_9_to_10(conn: &mut Connection) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/010_seen_at.sql"))?;
+    tx.pragma_update(None, "user_version", 10)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v9 -> v10");
+    Ok(10)
+}
+
+fn mig_10_to_11(conn: &mut Connection) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/011_format.sql"))?;
+    tx.pragma_update(None, "user_version", 11)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v10 -> v11");
+    Ok(11)
+}
+
+fn mig_11_to_12(conn: &mut Connection) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/012_foreign_keys.sql"))?;
+    tx.pragma_update(None, "user_version", 12)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v11 -> v12");
+    Ok(12)
+}
+
+fn mig_12_to_13(conn: &mut Connection, strict: bool) -> Result<usize, Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(include_str!("../migrations/013_content_len.sql"))?;
+    {
+        let mut stmt = tx.prepare("UPDATE events SET content_len = ? WHERE rowid = ?;")?;
+        backfill(&tx, strict, |rowid, event| {
+            let len: usize = event.content().len();
+            if len > 0 {
+                stmt.execute((len, rowid))?;
+            }
+            Ok(())
+        })?;
+    }
+    tx.pragma_update(None, "user_version", 13)?;
+    tx.commit()?;
+    tracing::info!("database schema upgraded v12 -> v13");
+    Ok(13)
+}
+
+/// Decode the stored events, in batches of [`BACKFILL_BATCH_SIZE`], to fill a new column or table
+///
+/// Only a batch at time is kept in memory, so it can be used on large databases. The rows that
+/// can't be decoded are logged and left untouched (handled when building the indexes, see
+/// [`SQLiteOptions::delete_corrupt`](crate::SQLiteOptions::delete_corrupt)), unless `strict`.
+pub(crate) fn backfill<F>(conn: &Connection, strict: bool, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(i64, Event) -> Result<(), Error>,
+{
+    // The `compression` and `format` columns don't exist before v6 and v11
+    let has_column = |name: &str| -> Result<bool, Error> {
+        Ok(conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('events') WHERE name = ?);",
+            [name],
+            |row| row.get(0),
+        )?)
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT rowid, event, {}, {} FROM events WHERE rowid > ? ORDER BY rowid ASC LIMIT ?;",
+        if has_column("compression")? {
+            "compression"
+        } else {
+            "0"
+        },
+        if has_column("format")? { "format" } else { "0" }
+    ))?;
+    let mut last_rowid: i64 = 0;
+    let mut total: usize = 0;
+    loop {
+        let mut batch: Vec<(i64, Vec<u8>, u8, u8)> = Vec::with_capacity(BACKFILL_BATCH_SIZE);
+        {
+            let mut rows = stmt.query((last_rowid, BACKFILL_BATCH_SIZE))?;
+            while let Some(row) = rows.next()? {
+                batch.push((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?));
+            }
+        }
+
+        let len: usize = batch.len();
+        for (rowid, value, compression, format) in batch.into_iter() {
+            last_rowid = rowid;
+            match decode_event(value, compression, format) {
+                Ok(event) => f(rowid, event)?,
+                Err(e) if strict => return Err(e),
+                Err(e) => tracing::error!("Impossible to decode event at rowid {rowid}: {e}"),
+            }
+        }
+
+        total += len;
+        if len < BACKFILL_BATCH_SIZE {
+            break;
+        }
+    }
+    tracing::debug!("Backfilled {total} events");
+    Ok(())
+}
+
+/// Re-encode the stored events matching the SQL `condition` (i.e. written in an older format),
+/// updating the rows in place
+///
+/// `encode` returns the new value, compression and format of each event. The rows are
+/// rewritten in batches of [`BACKFILL_BATCH_SIZE`], each committed in its own transaction:
+/// if interrupted, the next run continues from the rows not converted yet. Returns the number
+/// of rewritten rows.
+pub(crate) fn reencode<F>(
+    conn: &mut Connection,
+    condition: &str,
+    mut encode: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(&Event) -> Result<(Vec<u8>, u8, u8), Error>,
+{
+    let select: String = format!(
+        "SELECT rowid, event, compression, format FROM events WHERE rowid > ? AND ({condition}) ORDER BY rowid ASC LIMIT ?;"
+    );
+    let mut last_rowid: i64 = 0;
+    let mut total: usize = 0;
+    loop {
+        let tx = conn.transaction()?;
+        let mut batch: Vec<(i64, Vec<u8>, u8, u8)> = Vec::with_capacity(BACKFILL_BATCH_SIZE);
+        {
+            let mut stmt = tx.prepare_cached(&select)?;
+            let mut rows = stmt.query((last_rowid, BACKFILL_BATCH_SIZE))?;
+            while let Some(row) = rows.next()? {
+                batch.push((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?));
+            }
+        }
+
+        let len: usize = batch.len();
+        {
+            let mut stmt = tx.prepare_cached(
+                "UPDATE events SET event = ?, compression = ?, format = ? WHERE rowid = ?;",
+            )?;
+            for (rowid, value, compression, format) in batch.into_iter() {
+                let event: Event = decode_event(value, compression, format)?;
+                let (value, compression, format) = encode(&event)?;
+                stmt.execute((value, compression, format, rowid))?;
+                last_rowid = rowid;
+            }
+        }
+        tx.commit()?;
+
+        total += len;
+        if len < BACKFILL_BATCH_SIZE {
+            break;
+        }
+    }
+    tracing::debug!("Re-encoded {total} events");
+    Ok(total)
+}
@@ -0,0 +1,596 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! SQLite options
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+use crate::{Error, StorageFormat};
+
+/// Journal mode
+///
+/// <https://www.sqlite.org/pragma.html#pragma_journal_mode>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum JournalMode {
+    /// Rollback journal, deleted at the end of each transaction
+    Delete,
+    /// Rollback journal, truncated at the end of each transaction
+    Truncate,
+    /// Rollback journal, header zeroed at the end of each transaction
+    Persist,
+    /// Rollback journal kept in memory
+    Memory,
+    /// Write-Ahead Log (default)
+    #[default]
+    Wal,
+    /// No rollback journal
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+            Self::Persist => "PERSIST",
+            Self::Memory => "MEMORY",
+            Self::Wal => "WAL",
+            Self::Off => "OFF",
+        }
+    }
+}
+
+/// Synchronous level: how often SQLite waits for the data to be written to disk
+///
+/// <https://www.sqlite.org/pragma.html#pragma_synchronous>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Synchronous {
+    /// Never sync: fastest, but the database may be corrupted if the OS crashes or loses power
+    /// (an application crash is safe)
+    Off,
+    /// Sync at the critical moments (default)
+    ///
+    /// With [`JournalMode::Wal`] the database can't be corrupted, but the last committed
+    /// transactions may be rolled back after a power loss.
+    #[default]
+    Normal,
+    /// Sync on every commit: committed transactions survive a power loss
+    Full,
+    /// Like [`Synchronous::Full`], also syncing the directory of the rollback journal
+    Extra,
+}
+
+impl Synchronous {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Auto-vacuum mode: if the free pages are released to the filesystem after deletions
+///
+/// <https://www.sqlite.org/pragma.html#pragma_auto_vacuum>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AutoVacuum {
+    /// Free pages are reused, the file never shrinks (SQLite default)
+    None,
+    /// Free pages are released at every commit
+    Full,
+    /// Free pages are released only by
+    /// [`SQLiteDatabase::incremental_vacuum`](crate::SQLiteDatabase::incremental_vacuum)
+    Incremental,
+}
+
+impl AutoVacuum {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "NONE",
+            Self::Full => "FULL",
+            Self::Incremental => "INCREMENTAL",
+        }
+    }
+}
+
+/// Where the temporary tables and indices (i.e. used by large sorts and `VACUUM`) are stored
+///
+/// <https://www.sqlite.org/pragma.html#pragma_temp_store>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TempStore {
+    /// Compile-time default of SQLite: files (default)
+    #[default]
+    Default,
+    /// Temporary files, in [`SQLiteOptions::temp_dir`] or in the system temporary directory
+    File,
+    /// Memory
+    Memory,
+}
+
+impl TempStore {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "DEFAULT",
+            Self::File => "FILE",
+            Self::Memory => "MEMORY",
+        }
+    }
+}
+
+/// SQLite options
+#[derive(Debug, Clone)]
+pub struct SQLiteOptions {
+    journal_mode: JournalMode,
+    pub(crate) synchronous: Synchronous,
+    wal_autocheckpoint: u32,
+    busy_timeout: Duration,
+    cache_size: Option<u64>,
+    mmap_size: u64,
+    pub(crate) statement_cache_capacity: usize,
+    temp_store: TempStore,
+    auto_vacuum: Option<AutoVacuum>,
+    page_size: Option<u32>,
+    temp_dir: Option<PathBuf>,
+    foreign_keys: bool,
+    pub(crate) file_mode: Option<u32>,
+    pub(crate) read_only: bool,
+    pub(crate) read_connections: usize,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) strict_decode: bool,
+    pub(crate) delete_corrupt: bool,
+    pub(crate) compression: Option<i32>,
+    pub(crate) storage_format: StorageFormat,
+    pub(crate) import_checkpoint_threshold: Option<u64>,
+    pub(crate) flatbuffer_capacity: usize,
+    pub(crate) lazy_indexes: bool,
+    pub(crate) optimize_every: Option<u64>,
+    pub(crate) checkpoint_interval: Option<Duration>,
+    pub(crate) store_ephemeral: bool,
+    pub(crate) max_seen_entries: Option<usize>,
+    #[cfg(feature = "load_extension")]
+    extensions: Vec<PathBuf>,
+}
+
+impl Default for SQLiteOptions {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            wal_autocheckpoint: 1000,
+            busy_timeout: Duration::from_secs(5),
+            cache_size: None,
+            mmap_size: 16 * 1024 * 1024 * 1024,
+            statement_cache_capacity: 64,
+            temp_store: TempStore::default(),
+            auto_vacuum: None,
+            page_size: None,
+            temp_dir: None,
+            foreign_keys: true,
+            file_mode: None,
+            read_only: false,
+            read_connections: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            timeout: None,
+            strict_decode: false,
+            delete_corrupt: false,
+            compression: None,
+            storage_format: StorageFormat::default(),
+            import_checkpoint_threshold: None,
+            flatbuffer_capacity: 70_000,
+            lazy_indexes: false,
+            optimize_every: None,
+            checkpoint_interval: None,
+            store_ephemeral: false,
+            max_seen_entries: None,
+            #[cfg(feature = "load_extension")]
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl SQLiteOptions {
+    /// New default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Journal mode (default: [`JournalMode::Wal`])
+    ///
+    /// Applied when the connection is created, before any migration, and re-applied after the
+    /// migrations (the first one switches the new databases to WAL) and after `wipe` (which resets
+    /// the database with a `VACUUM`).
+    pub fn journal_mode(mut self, mode: JournalMode) -> Self {
+        self.journal_mode = mode;
+        self
+    }
+
+    /// Synchronous level (default: [`Synchronous::Normal`])
+    ///
+    /// See [`Synchronous`] for the crash-safety of each level. To change it only temporarily
+    /// (i.e. during a bulk import) use [`SQLiteDatabase::with_synchronous`](crate::SQLiteDatabase::with_synchronous).
+    pub fn synchronous(mut self, level: Synchronous) -> Self {
+        self.synchronous = level;
+        self
+    }
+
+    /// Number of WAL pages after which an automatic checkpoint is run (default: 1000)
+    ///
+    /// Has effect only with [`JournalMode::Wal`]. Set to `0` to disable automatic checkpoints.
+    pub fn wal_autocheckpoint(mut self, pages: u32) -> Self {
+        self.wal_autocheckpoint = pages;
+        self
+    }
+
+    /// Truncate the WAL at the end of a bulk import if it's larger than `bytes` (default: `None`)
+    ///
+    /// The automatic checkpoints don't shrink the WAL file, which may grow to the size of the
+    /// whole import. See [`SQLiteDatabase::checkpoint`](crate::SQLiteDatabase::checkpoint).
+    pub fn import_checkpoint_threshold(mut self, bytes: Option<u64>) -> Self {
+        self.import_checkpoint_threshold = bytes;
+        self
+    }
+
+    /// How long SQLite retries when the database is locked by another connection (default: 5 sec)
+    ///
+    /// If the lock isn't released within the timeout, the operation fails with `SQLITE_BUSY`.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Page cache size per connection, in KiB (default: SQLite default, 2000 KiB)
+    ///
+    /// A larger cache reduces the reads from disk on large databases, at the cost of memory:
+    /// the cache is allocated for every connection of the pool.
+    pub fn cache_size(mut self, kib: u64) -> Self {
+        self.cache_size = Some(kib);
+        self
+    }
+
+    /// Maximum number of bytes of the database file accessed with memory-mapped I/O
+    /// (default: 16 GiB)
+    ///
+    /// Memory-mapping speeds up the reads (i.e. building the indexes at startup) by avoiding a
+    /// copy between the kernel and the page cache, but it consumes virtual address space (per
+    /// connection) and an I/O error on the mapped file crashes the process instead of returning
+    /// an error. With [`JournalMode::Wal`] only the main database file is mapped: the pages
+    /// still in the WAL are read with regular I/O. Set to `0` to disable it.
+    pub fn mmap_size(mut self, bytes: u64) -> Self {
+        self.mmap_size = bytes;
+        self
+    }
+
+    /// Number of prepared statements cached by each connection (default: 64)
+    ///
+    /// The statements with a variable number of parameters (i.e. batches of IDs) are cached for
+    /// each size: a cache too small for them evicts (and prepares again) the common ones. The
+    /// statements built from the query filters aren't cached.
+    pub fn statement_cache_capacity(mut self, capacity: usize) -> Self {
+        self.statement_cache_capacity = capacity;
+        self
+    }
+
+    /// Auto-vacuum mode (default: `None`, unchanged)
+    ///
+    /// Takes effect only if set when the database is created, before the first table: on an
+    /// existing database the new mode is applied by the next [`SQLiteDatabase::vacuum`](crate::SQLiteDatabase::vacuum)
+    /// (a full rewrite of the file).
+    pub fn auto_vacuum(mut self, mode: Option<AutoVacuum>) -> Self {
+        self.auto_vacuum = mode;
+        self
+    }
+
+    /// Page size in bytes, a power of two between 512 and 65536 (default: `None`, SQLite default)
+    ///
+    /// Larger pages may speed up blob-heavy workloads. Like [`SQLiteOptions::auto_vacuum`],
+    /// takes effect only when the database is created: existing databases are converted by
+    /// [`SQLiteDatabase::set_page_size`](crate::SQLiteDatabase::set_page_size).
+    pub fn page_size(mut self, bytes: Option<u32>) -> Self {
+        self.page_size = bytes;
+        self
+    }
+
+    /// Where the temporary tables and indices are stored (default: [`TempStore::Default`])
+    pub fn temp_store(mut self, temp_store: TempStore) -> Self {
+        self.temp_store = temp_store;
+        self
+    }
+
+    /// Directory of the temporary files (default: `None`, `SQLITE_TMPDIR` or the system
+    /// temporary directory)
+    ///
+    /// Large sorts and `VACUUM` may need as much temporary space as the database: point it to a
+    /// disk with enough free space if the system temporary directory is small (i.e. a tmpfs).
+    /// The directory must exist. It's set with `PRAGMA temp_store_directory`, so it applies to
+    /// all the SQLite databases of the process.
+    pub fn temp_dir<P>(mut self, dir: Option<P>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.temp_dir = dir.map(|dir| dir.into());
+        self
+    }
+
+    /// Enforce the foreign keys, with `PRAGMA foreign_keys` (default: true)
+    ///
+    /// The deletions of the events cascade to their tags and coordinates. When disabled, they're
+    /// deleted by hand instead, with an extra statement for each table.
+    pub fn foreign_keys(mut self, enforce: bool) -> Self {
+        self.foreign_keys = enforce;
+        self
+    }
+
+    /// Permissions of the database file, set when it's created (default: `None`, from the umask)
+    ///
+    /// I.e. `0o600` to make it readable only by the owner. SQLite creates the `-wal` and `-shm`
+    /// files with the same permissions. The existing files aren't changed. Unix only: ignored on
+    /// the other platforms.
+    pub fn file_mode(mut self, mode: Option<u32>) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Open the database in read-only mode (default: false)
+    ///
+    /// Migrations are not executed: the database must already be at the latest schema version.
+    /// All the write operations return [`Error::ReadOnly`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Number of read-only connections, used for queries alongside the writer connection
+    /// (default: number of CPUs)
+    ///
+    /// Has effect only with [`JournalMode::Wal`], that allows readers to proceed while writing.
+    /// In-memory databases always use a single connection. Set to `0` to use only the writer.
+    pub fn read_connections(mut self, num: usize) -> Self {
+        self.read_connections = num;
+        self
+    }
+
+    /// Maximum time to wait for each database operation (default: no timeout)
+    ///
+    /// Includes the time waiting for a connection held by other operations. If it elapses,
+    /// [`Error::Timeout`] is returned, but the SQLite statement can't be aborted: it keeps
+    /// running in background (holding the connection) until completed.
+    /// Long operations, like `wipe` or `vacuum` on a large database, may need a larger timeout.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fail to open the database if a stored event can't be decoded (default: false)
+    ///
+    /// By default, the events that can't be decoded when building the indexes are logged and
+    /// skipped, so that a single corrupt row doesn't make the whole database unusable. The same
+    /// applies to `query`, that otherwise fails if one of the matching events can't be decoded.
+    pub fn strict_decode(mut self, strict: bool) -> Self {
+        self.strict_decode = strict;
+        self
+    }
+
+    /// Delete the events that can't be decoded when building the indexes (default: false)
+    ///
+    /// Ignored if [`SQLiteOptions::strict_decode`] or [`SQLiteOptions::read_only`] are enabled.
+    pub fn delete_corrupt(mut self, delete: bool) -> Self {
+        self.delete_corrupt = delete;
+        self
+    }
+
+    /// Compress the new events with zstd, at the given level (default: `None`, uncompressed)
+    ///
+    /// The compression is recorded for each row, so the events already stored (compressed or
+    /// not) are still read after changing this option. Small events, like reactions, barely
+    /// shrink: the savings come from events with a large content (i.e. long-form articles,
+    /// contact lists). The `save_events_zstd` bench (`just bench`) prints the database size
+    /// without and with it: 5.0 MB and 1.4 MB (level 3) for 1000 articles of 3 KB, whose
+    /// content is more repetitive than the real ones.
+    #[cfg(feature = "zstd")]
+    pub fn compression(mut self, level: Option<i32>) -> Self {
+        self.compression = level;
+        self
+    }
+
+    /// Initial capacity, in bytes, of the builder used to encode the events (default: 70000)
+    ///
+    /// The builder is shared by all the writes. If an event doesn't fit, the builder grows and,
+    /// once the event is encoded, it's replaced by a new one of this capacity.
+    pub fn flatbuffer_capacity(mut self, bytes: usize) -> Self {
+        self.flatbuffer_capacity = bytes;
+        self
+    }
+
+    /// Don't build the indexes when opening the database (default: false)
+    ///
+    /// The indexes are built on the first operation that needs them (i.e. `query`, `count`,
+    /// `delete`, the deletion checks). Until then, the saved events are indexed one by one but
+    /// not checked against the stored ones: the events they replace or delete are discarded
+    /// once the indexes are built.
+    pub fn lazy_indexes(mut self, lazy: bool) -> Self {
+        self.lazy_indexes = lazy;
+        self
+    }
+
+    /// Serialization format of the new events (default: [`StorageFormat::FlatBuffer`])
+    ///
+    /// The format is recorded for each row: the events already stored are still read after
+    /// changing it. JSON keeps every field of the events, at the cost of a larger database and
+    /// slower queries and startup.
+    pub fn storage_format(mut self, format: StorageFormat) -> Self {
+        self.storage_format = format;
+        self
+    }
+
+    /// Run [`SQLiteDatabase::optimize`](crate::SQLiteDatabase::optimize) every `writes` stored
+    /// events (default: `None`, only on close)
+    pub fn optimize_every(mut self, writes: Option<u64>) -> Self {
+        self.optimize_every = writes.filter(|w| *w > 0);
+        self
+    }
+
+    /// Run [`SQLiteDatabase::flush`](crate::SQLiteDatabase::flush) every `interval` in a
+    /// background task (default: `None`, disabled)
+    ///
+    /// Bounds the size of the WAL to replay after a crash. The task stops when the database is
+    /// dropped (if [`SQLiteDatabase::close`](crate::SQLiteDatabase::close) is called while it's
+    /// flushing, it returns `Error::StillInUse`). Ignored in read-only mode.
+    pub fn checkpoint_interval(mut self, interval: Option<Duration>) -> Self {
+        self.checkpoint_interval = interval.filter(|i| !i.is_zero());
+        self
+    }
+
+    /// Write the ephemeral events (NIP-16, kinds `20000-29999`) to the database (default: false)
+    ///
+    /// By default they're rejected by `save_event` (returning `false`) and skipped by
+    /// `bulk_import`. If enabled, they're stored but never indexed: they're returned by
+    /// `event_by_id`, but not by `query` or `count`.
+    pub fn store_ephemeral(mut self, store: bool) -> Self {
+        self.store_ephemeral = store;
+        self
+    }
+
+    /// Max number of rows kept in the table of the relays that have seen the events (default:
+    /// `None`, unbounded)
+    ///
+    /// When exceeded, the oldest entries are dropped with
+    /// [`SQLiteDatabase::trim_seen`](crate::SQLiteDatabase::trim_seen). The check runs every
+    /// 10% of the limit inserted entries, so the table can temporarily grow a bit over it.
+    pub fn max_seen_entries(mut self, max: Option<usize>) -> Self {
+        self.max_seen_entries = max.filter(|m| *m > 0);
+        self
+    }
+
+    /// SQLite extensions (shared libraries) to load on every connection (default: none)
+    ///
+    /// Loading is enabled only while loading these extensions. Extensions run native code with
+    /// full access to the process: only load trusted libraries.
+    #[cfg(feature = "load_extension")]
+    pub fn extensions<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.extensions = paths.into_iter().map(|path| path.into()).collect();
+        self
+    }
+
+    /// Number of read connections to open
+    pub(crate) fn num_readers(&self) -> usize {
+        if self.journal_mode == JournalMode::Wal {
+            self.read_connections
+        } else {
+            0
+        }
+    }
+
+    /// Apply options to the connection
+    ///
+    /// The journal mode can't be changed inside a transaction, so this must be called before
+    /// executing the migrations.
+    pub(crate) fn apply(&self, conn: &Connection) -> Result<(), Error> {
+        conn.busy_timeout(self.busy_timeout)?;
+
+        // The auto-vacuum and journal modes can't be changed on a read-only connection
+        if !self.read_only {
+            // Must be set before the tables are created by the migrations (and, for the page
+            // size, before switching to WAL)
+            if let Some(mode) = self.auto_vacuum {
+                conn.pragma_update(None, "auto_vacuum", mode.as_str())?;
+            }
+            if let Some(bytes) = self.page_size {
+                conn.pragma_update(None, "page_size", bytes)?;
+            }
+
+            self.apply_journal_mode(conn)?;
+        }
+
+        conn.pragma_update(None, "synchronous", self.synchronous.as_str())?;
+        conn.pragma_update(None, "wal_autocheckpoint", self.wal_autocheckpoint)?;
+
+        // Off by default in SQLite: the deletions of the events cascade to their tags and
+        // coordinates (also during the migrations)
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)?;
+
+        // Process-wide setting: applied only once, with the writer
+        if let Some(dir) = &self.temp_dir {
+            conn.pragma_update(None, "temp_store_directory", dir.to_string_lossy())?;
+        }
+
+        self.apply_per_connection(conn)
+    }
+
+    /// Re-apply the options overridden by the migrations
+    ///
+    /// The first migration (`001_init.sql`) switches the new databases to WAL, with
+    /// `synchronous=NORMAL`. The foreign keys are enabled at every startup, after the
+    /// migrations.
+    pub(crate) fn apply_after_migrations(&self, conn: &Connection) -> Result<(), Error> {
+        if !self.read_only {
+            self.apply_journal_mode(conn)?;
+        }
+        conn.pragma_update(None, "synchronous", self.synchronous.as_str())?;
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)?;
+        Ok(())
+    }
+
+    fn apply_journal_mode(&self, conn: &Connection) -> Result<(), Error> {
+        let mode: String = conn.pragma_update_and_check(
+            None,
+            "journal_mode",
+            self.journal_mode.as_str(),
+            |row| row.get(0),
+        )?;
+        tracing::debug!("SQLite journal mode: {mode}");
+        Ok(())
+    }
+
+    /// Apply options to a read connection
+    pub(crate) fn apply_to_reader(&self, conn: &Connection) -> Result<(), Error> {
+        conn.busy_timeout(self.busy_timeout)?;
+        self.apply_per_connection(conn)
+    }
+
+    /// Options that must be set on every connection of the pool
+    fn apply_per_connection(&self, conn: &Connection) -> Result<(), Error> {
+        if let Some(kib) = self.cache_size {
+            // Negative values are in KiB, positive in pages
+            conn.pragma_update(None, "cache_size", -(kib as i64))?;
+        }
+        conn.pragma_update(None, "mmap_size", self.mmap_size)?;
+        conn.pragma_update(None, "temp_store", self.temp_store.as_str())?;
+        conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+        #[cfg(feature = "load_extension")]
+        self.load_extensions(conn)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "load_extension")]
+    #[allow(unsafe_code)]
+    fn load_extensions(&self, conn: &Connection) -> Result<(), Error> {
+        if self.extensions.is_empty() {
+            return Ok(());
+        }
+
+        // SAFETY: the extensions are set by the application, that must trust them (see
+        // `SQLiteOptions::extensions`). The guard disables loading again when dropped.
+        unsafe {
+            let _guard = rusqlite::LoadExtensionGuard::new(conn)?;
+            for path in self.extensions.iter() {
+                conn.load_extension(path, None)
+                    .map_err(|error| Error::LoadExtension {
+                        path: path.clone(),
+                        error,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Connection pool
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use rusqlite::{Connection, InterruptHandle};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::Error;
+
+type Conn = Arc<Mutex<Connection>>;
+
+/// Interrupt handles of all the connections
+///
+/// Taken when the pool is created, since the connections are locked while running.
+#[derive(Clone)]
+struct Interrupts(Arc<Vec<InterruptHandle>>);
+
+impl fmt::Debug for Interrupts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Interrupts").field(&self.0.len()).finish()
+    }
+}
+
+/// SQLite connection pool
+///
+/// Writes are serialized on a single connection, while reads are distributed on the read-only
+/// connections (if any, otherwise the writer is used).
+#[derive(Debug, Clone)]
+pub(crate) struct Pool {
+    writer: Conn,
+    readers: Arc<Vec<Conn>>,
+    next_reader: Arc<AtomicUsize>,
+    interrupts: Interrupts,
+    timeout: Option<Duration>,
+}
+
+/// Weak reference to a [`Pool`], that doesn't keep the connections open
+#[derive(Debug, Clone)]
+pub(crate) struct WeakPool {
+    writer: Weak<Mutex<Connection>>,
+    readers: Weak<Vec<Conn>>,
+    next_reader: Weak<AtomicUsize>,
+    interrupts: Weak<Vec<InterruptHandle>>,
+    timeout: Option<Duration>,
+}
+
+impl WeakPool {
+    /// Get the [`Pool`], if not dropped yet
+    pub fn upgrade(&self) -> Option<Pool> {
+        Some(Pool {
+            writer: self.writer.upgrade()?,
+            readers: self.readers.upgrade()?,
+            next_reader: self.next_reader.upgrade()?,
+            interrupts: Interrupts(self.interrupts.upgrade()?),
+            timeout: self.timeout,
+        })
+    }
+}
+
+impl Pool {
+    /// New pool from already configured [`Connection`]s
+    ///
+    /// If `timeout` is set, every operation fails with [`Error::Timeout`] if not completed in time.
+    pub fn new(writer: Connection, readers: Vec<Connection>, timeout: Option<Duration>) -> Self {
+        let interrupts: Vec<InterruptHandle> = std::iter::once(&writer)
+            .chain(readers.iter())
+            .map(|conn| conn.get_interrupt_handle())
+            .collect();
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            readers: Arc::new(
+                readers
+                    .into_iter()
+                    .map(|conn| Arc::new(Mutex::new(conn)))
+                    .collect(),
+            ),
+            next_reader: Arc::new(AtomicUsize::new(0)),
+            interrupts: Interrupts(Arc::new(interrupts)),
+            timeout,
+        }
+    }
+
+    /// Weak reference to the pool (i.e. for the background tasks)
+    pub fn downgrade(&self) -> WeakPool {
+        WeakPool {
+            writer: Arc::downgrade(&self.writer),
+            readers: Arc::downgrade(&self.readers),
+            next_reader: Arc::downgrade(&self.next_reader),
+            interrupts: Arc::downgrade(&self.interrupts.0),
+            timeout: self.timeout,
+        }
+    }
+
+    /// Check if the pool has read connections (otherwise the reads use the writer)
+    pub fn has_readers(&self) -> bool {
+        !self.readers.is_empty()
+    }
+
+    /// Run a closure on a blocking thread with exclusive access to the writer connection
+    pub async fn interact<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.with_timeout(async {
+            let conn = self.writer.clone().lock_owned().await;
+            run(conn, f).await
+        })
+        .await
+    }
+
+    /// Run a closure on a blocking thread with exclusive access to a read connection
+    ///
+    /// The first idle reader is used, otherwise waits for one of them (round-robin).
+    pub async fn interact_read<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.with_timeout(async {
+            let conn = self.reader().await;
+            run(conn, f).await
+        })
+        .await
+    }
+
+    /// Interrupt the statements running on all the connections
+    ///
+    /// The interrupted operations fail with [`Error::Interrupted`]. No-op for the idle
+    /// connections: the next operations run normally.
+    pub fn interrupt_all(&self) {
+        for handle in self.interrupts.0.iter() {
+            handle.interrupt();
+        }
+    }
+
+    /// Close all the connections, after running `f` on the writer
+    ///
+    /// Fails with [`Error::StillInUse`] if the pool has other clones, or if a connection is still
+    /// held by an operation (i.e. one that timed out): the connections are then closed when the
+    /// last clone, or operation, drops them. A busy reader would keep its snapshot open, so the
+    /// WAL couldn't be truncated by `f`.
+    pub async fn close<F>(self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Connection) -> Result<(), Error> + Send + 'static,
+    {
+        let writer: Mutex<Connection> =
+            Arc::try_unwrap(self.writer).map_err(|_| Error::StillInUse)?;
+        let readers: Vec<Conn> = Arc::try_unwrap(self.readers).map_err(|_| Error::StillInUse)?;
+        let readers: Vec<Mutex<Connection>> = readers
+            .into_iter()
+            .map(Arc::try_unwrap)
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::StillInUse)?;
+
+        tokio::task::spawn_blocking(move || {
+            for reader in readers.into_iter() {
+                reader.into_inner().close().map_err(|(_, e)| e)?;
+            }
+
+            let writer: Connection = writer.into_inner();
+            f(&writer)?;
+            writer.close().map_err(|(_, e)| e)?;
+            Ok::<(), Error>(())
+        })
+        .await?
+    }
+
+    /// Bound the time waited for an operation (including the wait for the connection)
+    ///
+    /// The blocking task can't be aborted: on timeout it keeps running, holding the connection,
+    /// until the closure returns.
+    async fn with_timeout<Fut, R>(&self, fut: Fut) -> Result<R, Error>
+    where
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fut)
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => fut.await,
+        }
+    }
+
+    async fn reader(&self) -> OwnedMutexGuard<Connection> {
+        if self.readers.is_empty() {
+            return self.writer.clone().lock_owned().await;
+        }
+
+        for conn in self.readers.iter() {
+            if let Ok(guard) = conn.clone().try_lock_owned() {
+                return guard;
+            }
+        }
+
+        let index: usize = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[index].clone().lock_owned().await
+    }
+}
+
+async fn run<F, R>(mut conn: OwnedMutexGuard<Connection>, f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut Connection) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(move || f(&mut conn)).await?)
+}
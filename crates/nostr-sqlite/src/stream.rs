@@ -0,0 +1,36 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Event stream
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use nostr::Event;
+use tokio::sync::mpsc::Receiver;
+
+use crate::Error;
+
+/// Stream of [`Event`]s
+///
+/// Returned by [`SQLiteDatabase::query_stream`](crate::SQLiteDatabase::query_stream).
+#[derive(Debug)]
+pub struct EventStream {
+    rx: Receiver<Result<Event, Error>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(rx: Receiver<Result<Event, Error>>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Subscription to the saved events
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use nostr::{Event, Filter};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+/// Max number of saved events kept for the subscribers not yet notified
+pub(crate) const NOTIFICATIONS_CAPACITY: usize = 1024;
+
+type RecvFuture = Pin<Box<dyn Future<Output = (Result<Event, RecvError>, Receiver<Event>)> + Send>>;
+
+/// Stream of the newly saved [`Event`]s matching a [`Filter`]
+///
+/// Returned by [`SQLiteDatabase::subscribe`](crate::SQLiteDatabase::subscribe).
+pub struct Subscription {
+    filter: Filter,
+    fut: RecvFuture,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl Subscription {
+    pub(crate) fn new(filter: Filter, rx: Receiver<Event>) -> Self {
+        Self {
+            filter,
+            fut: Box::pin(recv(rx)),
+        }
+    }
+}
+
+async fn recv(mut rx: Receiver<Event>) -> (Result<Event, RecvError>, Receiver<Event>) {
+    let res = rx.recv().await;
+    (res, rx)
+}
+
+impl Stream for Subscription {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let (res, rx) = match self.fut.as_mut().poll(cx) {
+                Poll::Ready(output) => output,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.fut = Box::pin(recv(rx));
+
+            match res {
+                Ok(event) => {
+                    if self.filter.match_event(&event) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Subscription lagged behind: {skipped} saved events skipped");
+                }
+                Err(RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
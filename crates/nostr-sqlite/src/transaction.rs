@@ -0,0 +1,61 @@
+// Copyright (c) 2022-2023 Yuki Kishimoto
+// Copyright (c) 2023-2024 Rust Nostr Developers
+// Distributed under the MIT software license
+
+//! Transaction
+
+use nostr::{Event, EventId, Filter, Url};
+
+#[derive(Debug)]
+pub(crate) enum TransactionOperation {
+    SaveEvent(Event),
+    EventIdSeen(EventId, Url),
+    Delete(Filter),
+}
+
+/// Operations to execute atomically
+///
+/// Passed to [`SQLiteDatabase::transaction`](crate::SQLiteDatabase::transaction): the operations
+/// are only recorded here and executed, in order, once the closure returns.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    operations: Vec<TransactionOperation>,
+}
+
+impl Transaction {
+    /// Save [`Event`] (like [`NostrDatabase::save_event`](nostr_database::NostrDatabase::save_event))
+    pub fn save_event(&mut self, event: Event) {
+        self.operations.push(TransactionOperation::SaveEvent(event));
+    }
+
+    /// Set [`EventId`] as seen by relay (like [`NostrDatabase::event_id_seen`](nostr_database::NostrDatabase::event_id_seen))
+    pub fn event_id_seen(&mut self, event_id: EventId, relay_url: Url) {
+        self.operations
+            .push(TransactionOperation::EventIdSeen(event_id, relay_url));
+    }
+
+    /// Delete the events matching the [`Filter`] (like [`NostrDatabase::delete`](nostr_database::NostrDatabase::delete))
+    pub fn delete(&mut self, filter: Filter) {
+        self.operations.push(TransactionOperation::Delete(filter));
+    }
+
+    /// Number of recorded operations
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// Check if no operations have been recorded
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub(crate) fn has_deletions(&self) -> bool {
+        self.operations
+            .iter()
+            .any(|op| matches!(op, TransactionOperation::Delete(_)))
+    }
+
+    pub(crate) fn into_operations(self) -> Vec<TransactionOperation> {
+        self.operations
+    }
+}